@@ -8,6 +8,8 @@ use std::collections::HashMap;
 use std::sync::atomic::Ordering;
 
 use bytemuck::Pod;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 use bytemuck::Zeroable;
 use vulkano::buffer::BufferUsage;
 use vulkano::buffer::CpuAccessibleBuffer;
@@ -20,6 +22,24 @@ pub mod lane;
 pub mod vehicle;
 pub mod navigation;
 pub mod signal;
+pub mod congestion;
+pub mod hierarchy;
+pub mod event;
+pub mod lane_plan;
+pub mod restriction;
+pub mod alternatives;
+pub mod demand;
+pub mod following;
+pub mod lane_change;
+pub mod merge;
+pub mod vehicle_type;
+pub mod personality;
+pub mod collision;
+pub mod transit;
+pub mod actuated;
+pub mod controller;
+#[cfg(test)]
+pub(crate) mod testing;
 
 use crate::network::clip::*;
 use crate::network::band::*;
@@ -27,9 +47,22 @@ use crate::network::lane::*;
 use crate::network::vehicle::*;
 // use crate::network::navigation::*;
 use crate::network::signal::*;
+use crate::network::congestion::*;
+use crate::network::hierarchy::*;
+use crate::network::event::*;
+use crate::network::alternatives::*;
+use crate::network::demand::*;
+use crate::network::lane_change::*;
+use crate::network::merge::*;
+use crate::network::vehicle_type::*;
+use crate::network::collision::*;
+use crate::network::transit::*;
+use crate::network::actuated::*;
 
 pub const BATCH_COUNT: usize = 10;
 pub const LANE_MAX_BRANCH: u8 = 5;
+pub const LANE_SPEED: f32 = 100.0;
+pub const DAY_SECONDS: f64 = 86_400.0;
 
 // #[repr(C)]
 // #[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
@@ -109,6 +142,15 @@ impl NetworkVertex {
 	}
 }
 
+// Seeded so runs are reproducible. Use NetworkAllocation::seed to change it.
+pub struct NetworkRng(pub StdRng);
+
+impl Default for NetworkRng {
+	fn default() -> Self {
+		Self(StdRng::seed_from_u64(0))
+	}
+}
+
 #[derive(Default)]
 pub struct NetworkAllocation {
 	pub clips: Arc<RwLock<HashMap<u32, Arc<RwLock<Clip>>>>>,
@@ -117,6 +159,25 @@ pub struct NetworkAllocation {
 	pub vehicle_batches: Arc<RwLock<HashMap<u32, Arc<RwLock<VehicleBatch>>>>>,
 	pub staged_vehicle_batch: Arc<RwLock<Arc<RwLock<VehicleBatch>>>>,
 	pub unused_vehicle_batchs: Arc<RwLock<Vec<Arc<RwLock<VehicleBatch>>>>>,
+	// Batch id of every vehicle by vehicle id, the staged batch included.
+	pub vehicle_index: Arc<RwLock<HashMap<u32, u32>>>,
+	pub reroute_config: Arc<RwLock<RerouteConfig>>,
+	pub lane_change_config: Arc<RwLock<LaneChangeConfig>>,
+	pub merge_config: Arc<RwLock<MergeConfig>>,
+	pub vehicle_types: Arc<RwLock<VehicleTypes>>,
+	pub collision_config: Arc<RwLock<CollisionConfig>>,
+	pub collisions: Arc<RwLock<CollisionState>>,
+	// Route choice of vehicles spawned without one.
+	pub route_choice: Arc<RwLock<Option<RouteChoice>>>,
+	pub alternatives_cache: Arc<RwLock<AlternativesCache>>,
+	pub rng: Arc<RwLock<NetworkRng>>,
+	pub hierarchy: Arc<RwLock<Option<Arc<ContractionHierarchy>>>>,
+	pub events: Arc<RwLock<Vec<NetworkEvent>>>,
+	pub demands: Arc<RwLock<Vec<Demand>>>,
+	pub transit_lines: Arc<RwLock<Vec<TransitLine>>>,
+	pub actuated_controllers: Arc<RwLock<Vec<Arc<RwLock<ActuatedController>>>>>,
+	// Simulation seconds since midnight of the first day.
+	pub time: Arc<RwLock<f64>>,
 
 	pub clip_count: AtomicU32,
 	pub band_count: AtomicU32,
@@ -125,7 +186,30 @@ pub struct NetworkAllocation {
 	pub vehicle_batch_counter: AtomicU32,
 }
 
+impl Network {
+	// Runs one simulation step: clock, demands, signal controllers, vehicles,
+	// transit lines, collisions and batch compaction.
+	pub fn tick(
+		network: &Arc<Network>,
+		delta_time: f32
+	) {
+		let network_c = network.clone();
+		let allocation = network_allocation!(network_c);
+		allocation.advance_time(delta_time);
+		Demand::tick_all(network, delta_time);
+		ActuatedController::tick_all(allocation, delta_time);
+		allocation.tick_vehicles(delta_time);
+		TransitLine::tick_all(network, delta_time);
+		tick_collisions(allocation, delta_time);
+		allocation.compact_batches();
+	}
+}
+
 impl NetworkAllocation {
+	pub fn seed(&self, seed: u64) {
+		*self.rng.write().unwrap() = NetworkRng(StdRng::seed_from_u64(seed));
+	}
+
 	pub fn cycle_svb(&self) {
 		let mut wa_svb_con = self.staged_vehicle_batch.write().unwrap();
 		let svb_id = wa_svb_con.read().unwrap().id;
@@ -141,6 +225,37 @@ impl NetworkAllocation {
 		}
 	}
 
+	// Preprocesses the band graph so navigation answers band to band queries
+	// without A*. Has to be called again after the network is edited.
+	pub fn build_hierarchy(&self) {
+		let hierarchy = ContractionHierarchy::build(self);
+		*self.hierarchy.write().unwrap() = Some(Arc::new(hierarchy));
+	}
+
+	// Drops the hierarchy and the cached alternative routes, both only hold
+	// for the network they were built on.
+	pub fn invalidate_hierarchy(&self) {
+		*self.hierarchy.write().unwrap() = None;
+		self.alternatives_cache.write().unwrap().clear();
+	}
+
+	pub fn time(&self) -> f64 {
+		*self.time.read().unwrap()
+	}
+
+	// Call once per simulation step with the step's delta time.
+	pub fn advance_time(&self, delta_time: f32) {
+		*self.time.write().unwrap() += delta_time as f64;
+	}
+
+	pub fn push_event(&self, event: NetworkEvent) {
+		self.events.write().unwrap().push(event);
+	}
+
+	pub fn drain_events(&self) -> Vec<NetworkEvent> {
+		std::mem::take(&mut *self.events.write().unwrap())
+	}
+
 	pub fn clip(&self, clip_id: u32) -> Arc<RwLock<Clip>> {
 		let allocation_clips = self.clips.read().unwrap();
 		let clip_c = allocation_clips.get(&clip_id).expect("invalid clip id").clone();
@@ -165,6 +280,153 @@ impl NetworkAllocation {
 		vehicle_batch_c
 	}
 
+	// Batch holding the vehicle, which may be the staged batch. Use
+	// VehicleBatch::vehicle on it to get the vehicle.
+	pub fn vehicle_batch_of(&self, vehicle_id: u32) -> Option<Arc<RwLock<VehicleBatch>>> {
+		let batch_id = *self.vehicle_index.read().unwrap().get(&vehicle_id)?;
+		if let Some(x) = self.vehicle_batches.read().unwrap().get(&batch_id) {
+			return Some(x.clone());
+		}
+		let svb = self.staged_vehicle_batch.read().unwrap().clone();
+		let svb_id = svb.read().unwrap().id;
+		match svb_id == batch_id {
+			true => Some(svb),
+			false => None
+		}
+	}
+
+	// Despawns a vehicle, taking it out of its batch and lanes. An emptied
+	// batch is recycled. Must not be called while the vehicle's batch is
+	// locked.
+	pub fn remove_vehicle(&self, vehicle_id: u32) -> Option<Vehicle> {
+		let vehicle_batch = self.vehicle_batch_of(vehicle_id)?;
+		let mut wa_vehicle_batch = vehicle_batch.write().unwrap();
+		let vehicle = wa_vehicle_batch.remove(vehicle_id)?;
+		self.vehicle_index.write().unwrap().remove(&vehicle_id);
+
+		// LANES
+
+		let mut lanes: Vec<u32> = vec![vehicle.active_identity.lane];
+		if let Some(lane_change) = vehicle.lane_change {
+			lanes.push(lane_change.from.lane);
+		}
+		for lane in lanes {
+			let c_lane = self.lane(lane);
+			c_lane.write().unwrap().remove_vehicle(vehicle_id, vehicle.data.distance);
+		}
+
+		let batch_id = wa_vehicle_batch.id;
+		drop(wa_vehicle_batch);
+		self.recycle_batch(batch_id);
+		Some(vehicle)
+	}
+
+	// Moves the batch to the unused batches if it is empty. The staged batch
+	// is never recycled.
+	pub fn recycle_batch(&self, batch_id: u32) {
+		let mut wa_vbs = self.vehicle_batches.write().unwrap();
+		let empty = match wa_vbs.get(&batch_id) {
+			Some(x) => x.read().unwrap().is_empty(),
+			None => { return; }
+		};
+		if empty {
+			let vb = wa_vbs.remove(&batch_id).unwrap();
+			self.unused_vehicle_batchs.write().unwrap().push(vb);
+		}
+	}
+
+	// Ticks every vehicle and takes the ones that left the network out of
+	// their batch.
+	pub fn tick_vehicles(&self, delta_time: f32) {
+		let mut batches: Vec<Arc<RwLock<VehicleBatch>>> = self.vehicle_batches.read().unwrap().values().cloned().collect();
+		batches.push(self.staged_vehicle_batch.read().unwrap().clone());
+		for vehicle_batch in batches {
+			let mut wa_vehicle_batch = vehicle_batch.write().unwrap();
+			let mut exited: Vec<VehicleIdentity> = Vec::new();
+			for vehicle in wa_vehicle_batch.iter_mut() {
+				if let TickStatus::DESTROY = vehicle.tick_temp(self, delta_time) {
+					exited.push(vehicle.data.identity);
+				}
+			}
+			for identity in exited {
+				wa_vehicle_batch.remove(identity.sub);
+				self.vehicle_index.write().unwrap().remove(&identity.sub);
+				self.push_event(NetworkEvent::VehicleExited {
+					vehicle: identity
+				});
+			}
+			let batch_id = wa_vehicle_batch.id;
+			drop(wa_vehicle_batch);
+			self.recycle_batch(batch_id);
+		}
+	}
+
+	// Merges batches less than half full into each other, fullest first.
+	// Batches emptied this way are recycled.
+	pub fn compact_batches(&self) {
+		let mut sparse: Vec<(usize, Arc<RwLock<VehicleBatch>>)> = self.vehicle_batches.read().unwrap().values().map(
+			|x|
+			(x.read().unwrap().len(), x.clone())
+		).filter(
+			|x|
+			x.0 < BATCH_COUNT / 2
+		).collect();
+		if sparse.len() < 2 {
+			return;
+		}
+		sparse.sort_by_key(
+			|x|
+			(usize::MAX - x.0, x.1.read().unwrap().id)
+		);
+		let mut target = 0;
+		let mut source = sparse.len() - 1;
+		while target < source {
+			let mut wa_target = sparse[target].1.write().unwrap();
+			let mut wa_source = sparse[source].1.write().unwrap();
+			while wa_target.len() < BATCH_COUNT {
+				let sub = match wa_source.last() {
+					Some(x) => x.data.identity.sub,
+					None => { break; }
+				};
+				let mut vehicle = wa_source.remove(sub).unwrap();
+				self.move_vehicle(&mut vehicle, wa_target.id);
+				wa_target.push(vehicle);
+			}
+			let full = wa_target.len() >= BATCH_COUNT;
+			let source_id = wa_source.id;
+			let empty = wa_source.is_empty();
+			drop(wa_source);
+			drop(wa_target);
+			if empty {
+				self.recycle_batch(source_id);
+				source -= 1;
+			}
+			if full {
+				target += 1;
+			}
+		}
+	}
+
+	// Points the vehicle, its lane copies and the index at another batch.
+	fn move_vehicle(&self, vehicle: &mut Vehicle, batch_id: u32) {
+		let sub = vehicle.data.identity.sub;
+		vehicle.data.identity.batch = batch_id;
+		let mut lanes: Vec<u32> = vec![vehicle.active_identity.lane];
+		if let Some(lane_change) = vehicle.lane_change {
+			lanes.push(lane_change.from.lane);
+		}
+		for lane in lanes {
+			let c_lane = self.lane(lane);
+			c_lane.write().unwrap().update_vehicle(
+				sub,
+				vehicle.data.distance,
+				|x|
+				x.identity.batch = batch_id
+			);
+		}
+		self.vehicle_index.write().unwrap().insert(sub, batch_id);
+	}
+
 	pub fn build(&self, device: &Arc<Device>) -> (Arc<CpuAccessibleBuffer<[NetworkVertex]>>, Arc<CpuAccessibleBuffer<[u32]>>) {
 		
 		// COLLECT NETWORK BUFFERS
@@ -206,4 +468,122 @@ impl NetworkAllocation {
 			).unwrap()
 		)
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::network::{testing, vehicle::{Vehicle, VehicleIdentity, SpawnParams}, event::NetworkEvent, Network, BATCH_COUNT};
+
+	// The vehicle's batch from the index, its own identity and its lane copy
+	// all agree.
+	fn indexed(network: &Arc<Network>, identity: VehicleIdentity) -> bool {
+		let allocation = &network.allocation;
+		let vehicle_batch = match allocation.vehicle_batch_of(identity.sub) {
+			Some(x) => x,
+			None => { return false; }
+		};
+		let ra_vehicle_batch = vehicle_batch.read().unwrap();
+		let vehicle = match ra_vehicle_batch.vehicle(identity.sub) {
+			Some(x) => x,
+			None => { return false; }
+		};
+		let c_lane = allocation.lane(vehicle.active_identity.lane);
+		let ra_lane = c_lane.read().unwrap();
+		let copy = ra_lane.vehicles.iter().find(
+			|x|
+			x.identity.sub == identity.sub
+		);
+		vehicle.data.identity.batch == ra_vehicle_batch.id && copy.map(|x| x.identity.batch) == Some(ra_vehicle_batch.id)
+	}
+
+	#[test]
+	fn vehicles_are_found_across_batches() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let ids: Vec<VehicleIdentity> = (0..BATCH_COUNT * 2 + 5).map(
+			|_|
+			Vehicle::new(&network, lanes[0], lanes[6])
+		).collect();
+		assert!(ids.iter().all(|x| indexed(&network, *x)));
+		for id in ids.iter().take(BATCH_COUNT) {
+			assert!(allocation.remove_vehicle(id.sub).is_some());
+			assert!(allocation.vehicle_batch_of(id.sub).is_none());
+		}
+		// The emptied batch is unused again.
+		assert_eq!(allocation.vehicle_batches.read().unwrap().len(), 1);
+		assert_eq!(allocation.unused_vehicle_batchs.read().unwrap().len(), 1);
+		assert!(ids.iter().skip(BATCH_COUNT).all(|x| indexed(&network, *x)));
+	}
+
+	#[test]
+	fn compaction_keeps_the_index() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let ids: Vec<VehicleIdentity> = (0..BATCH_COUNT * 2).map(
+			|_|
+			Vehicle::new(&network, lanes[0], lanes[6])
+		).collect();
+		// Three left in each of the two full batches.
+		let mut kept: Vec<VehicleIdentity> = Vec::new();
+		for batch in ids.chunks(BATCH_COUNT) {
+			for id in batch.iter().skip(3) {
+				allocation.remove_vehicle(id.sub);
+			}
+			kept.extend_from_slice(&batch[..3]);
+		}
+		assert_eq!(allocation.vehicle_batches.read().unwrap().len(), 2);
+		allocation.compact_batches();
+		assert_eq!(allocation.vehicle_batches.read().unwrap().len(), 1);
+		assert_eq!(allocation.unused_vehicle_batchs.read().unwrap().len(), 1);
+		assert!(kept.iter().all(|x| indexed(&network, *x)));
+	}
+
+	#[test]
+	fn batches_fill_before_cycling() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		for _ in 0..BATCH_COUNT - 1 {
+			Vehicle::new(&network, lanes[0], lanes[6]);
+		}
+		assert!(allocation.vehicle_batches.read().unwrap().is_empty());
+		Vehicle::new(&network, lanes[0], lanes[6]);
+		assert_eq!(allocation.vehicle_batches.read().unwrap().len(), 1);
+		assert!(allocation.staged_vehicle_batch.read().unwrap().read().unwrap().is_empty());
+	}
+
+	#[test]
+	fn sinks_take_vehicles_out() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		allocation.lane(lanes[0].lane).write().unwrap().sink = true;
+		// Fills the first batch, so the one leaving empties it.
+		let ids: Vec<VehicleIdentity> = (0..BATCH_COUNT).map(
+			|x|
+			Vehicle::spawn(&network, lanes[0], lanes[6], SpawnParams {
+				distance: 145.0 - x as f32 * 14.0,
+				speed: 10.0,
+				..Default::default()
+			}).unwrap()
+		).collect();
+		let mut exited: Vec<u32> = Vec::new();
+		for _ in 0..400 {
+			Network::tick(&network, 0.05);
+			for event in allocation.drain_events() {
+				if let NetworkEvent::VehicleExited { vehicle } = event {
+					exited.push(vehicle.sub);
+				}
+			}
+			if exited.len() == ids.len() {
+				break;
+			}
+		}
+		// In the order they reached the end, none went on to lane 3.
+		assert_eq!(exited, ids.iter().map(|x| x.sub).collect::<Vec<u32>>());
+		assert!(ids.iter().all(|x| allocation.vehicle_batch_of(x.sub).is_none()));
+		assert!(allocation.lane(lanes[0].lane).read().unwrap().vehicles.is_empty());
+		assert!(allocation.lane(lanes[2].lane).read().unwrap().vehicles.is_empty());
+		assert!(allocation.vehicle_batches.read().unwrap().is_empty());
+		assert_eq!(allocation.unused_vehicle_batchs.read().unwrap().len(), 1);
+	}
+}
@@ -0,0 +1,373 @@
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use super::{signal::{Signal, SignalIdentity, InstructResult, LightState, light_instruct}, vehicle::Vehicle, NetworkAllocation};
+
+// Stretch of a lane that reports whether a vehicle is on it. Distances are
+// along the lane, as SignalIdentity::signal_distance.
+#[derive(Debug, Clone, Copy)]
+pub struct Detector {
+	pub lane: u32,
+	// Of the detector's downstream end.
+	pub distance: f32,
+	pub length: f32,
+}
+
+impl Detector {
+	// Detector ending setback before the stop line of the signal.
+	pub fn before(
+		identity: &SignalIdentity,
+		setback: f32,
+		length: f32
+	) -> Self {
+		Self {
+			lane: identity.lane,
+			distance: (identity.signal_distance - setback).max(0.0),
+			length
+		}
+	}
+
+	pub fn occupied(&self, allocation: &NetworkAllocation) -> bool {
+		let c_lane = allocation.lane(self.lane);
+		let ra_lane = c_lane.read().unwrap();
+		let start = self.distance - self.length;
+		ra_lane.vehicles.iter().any(
+			|x|
+			x.distance >= start && x.distance - x.length <= self.distance
+		)
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ActuatedPhase {
+	// Any of them occupied calls the phase or, while it is green, extends it.
+	pub detectors: Vec<Detector>,
+	pub min_green: f32,
+	// Counted from the first call of another phase during the green, a
+	// phase resting in green runs on until then.
+	pub max_green: f32,
+	// Seconds the green is held after the detectors became free. Ending it
+	// then is a gap out.
+	pub passage: f32,
+	pub yellow: f32,
+	// Seconds every phase shows red after the yellow.
+	pub all_red: f32,
+	// Serve the phase every cycle, even without a vehicle waiting.
+	pub recall: bool,
+}
+
+impl Default for ActuatedPhase {
+	fn default() -> Self {
+		Self {
+			detectors: Vec::new(),
+			min_green: 10.0,
+			max_green: 50.0,
+			passage: 3.0,
+			yellow: 4.0,
+			all_red: 2.0,
+			recall: false
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseTermination {
+	// The detectors were free for the passage time.
+	GapOut,
+	// The green ran to its maximum with vehicles still arriving.
+	MaxOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Interval {
+	Green,
+	Yellow,
+	AllRed,
+}
+
+// Serves one phase at a time in order, skipping phases no vehicle waits
+// for. A green is held for its minimum, then until its detectors gap out or
+// it maxes out, as long as another phase is called. Without calls the
+// active phase rests in green. Tick it every step before the vehicles.
+#[derive(Debug)]
+pub struct ActuatedController {
+	pub phases: Vec<ActuatedPhase>,
+	// Phase that is green or changing to red.
+	pub active: usize,
+	pub last_termination: Option<PhaseTermination>,
+	interval: Interval,
+	// Seconds in the interval.
+	elapsed: f32,
+	// Seconds since a detector of the active phase was occupied.
+	gap: f32,
+	// Seconds since another phase was first called during the green.
+	max_timer: Option<f32>,
+	// Phases a vehicle waits for.
+	calls: Vec<bool>,
+	// Phase that turns green after the all red.
+	next: usize,
+}
+
+impl ActuatedController {
+	pub fn new(phases: Vec<ActuatedPhase>) -> Self {
+		let count = phases.len();
+		Self {
+			phases,
+			active: 0,
+			last_termination: None,
+			interval: Interval::Green,
+			elapsed: 0.0,
+			gap: 0.0,
+			max_timer: None,
+			calls: vec![false; count],
+			next: 0
+		}
+	}
+
+	pub fn called(&self, phase: usize) -> bool {
+		self.calls.get(phase).copied().unwrap_or(false)
+	}
+
+	// State the phase's signals show and the seconds until it changes at
+	// the latest.
+	pub fn state(&self, phase: usize) -> (LightState, f32) {
+		// Nothing to control.
+		if self.phases.is_empty() {
+			return (LightState::Green, f32::INFINITY);
+		}
+		if phase != self.active {
+			return (LightState::Red, f32::INFINITY);
+		}
+		let active = &self.phases[self.active];
+		match self.interval {
+			Interval::Green => {
+				let remaining = match self.max_timer {
+					Some(x) => (active.max_green - x).max(active.min_green - self.elapsed).max(0.0),
+					None => f32::INFINITY
+				};
+				(LightState::Green, remaining)
+			},
+			Interval::Yellow => (LightState::Yellow, (active.yellow - self.elapsed).max(0.0)),
+			Interval::AllRed => (LightState::Red, (active.all_red - self.elapsed).max(0.0))
+		}
+	}
+
+	pub fn tick(
+		&mut self,
+		allocation: &NetworkAllocation,
+		delta_time: f32
+	) {
+		if self.phases.is_empty() {
+			return;
+		}
+
+		// DETECTORS
+
+		let occupied: Vec<bool> = self.phases.iter().map(
+			|x|
+			x.detectors.iter().any(|d| d.occupied(allocation))
+		).collect();
+		let serving = self.interval == Interval::Green;
+		for (i, phase) in self.phases.iter().enumerate() {
+			if (occupied[i] || phase.recall) && !(serving && i == self.active) {
+				self.calls[i] = true;
+			}
+		}
+
+		// INTERVALS
+
+		self.elapsed += delta_time;
+		let active = &self.phases[self.active];
+		match self.interval {
+			Interval::Green => {
+				self.gap = match occupied[self.active] {
+					true => 0.0,
+					false => self.gap + delta_time
+				};
+				let next = match self.next_call() {
+					Some(x) => x,
+					None => { return; }
+				};
+				let max_timer = match self.max_timer {
+					Some(x) => x + delta_time,
+					None => 0.0
+				};
+				self.max_timer = Some(max_timer);
+				if self.elapsed < active.min_green {
+					return;
+				}
+				let termination = if self.gap >= active.passage {
+					PhaseTermination::GapOut
+				} else if max_timer >= active.max_green {
+					PhaseTermination::MaxOut
+				} else {
+					return;
+				};
+				self.last_termination = Some(termination);
+				self.next = next;
+				self.interval = Interval::Yellow;
+				self.elapsed = 0.0;
+			},
+			Interval::Yellow => {
+				if self.elapsed >= active.yellow {
+					self.interval = Interval::AllRed;
+					self.elapsed = 0.0;
+				}
+			},
+			Interval::AllRed => {
+				if self.elapsed >= active.all_red {
+					self.active = self.next;
+					self.calls[self.active] = false;
+					self.interval = Interval::Green;
+					self.elapsed = 0.0;
+					self.gap = 0.0;
+					self.max_timer = None;
+				}
+			}
+		}
+	}
+
+	// First called phase after the active one in order.
+	fn next_call(&self) -> Option<usize> {
+		let count = self.phases.len();
+		(1..count).map(
+			|x|
+			(self.active + x) % count
+		).find(
+			|x|
+			self.calls[*x]
+		)
+	}
+
+	// Ticks every controller in NetworkAllocation::actuated_controllers.
+	pub fn tick_all(
+		allocation: &NetworkAllocation,
+		delta_time: f32
+	) {
+		let ra_controllers = allocation.actuated_controllers.read().unwrap();
+		for controller in ra_controllers.iter() {
+			controller.write().unwrap().tick(allocation, delta_time);
+		}
+	}
+}
+
+// Light of a lane showing the state of one phase of a controller.
+#[derive(Debug, Clone)]
+pub struct ActuatedLight {
+	pub signal_identity: SignalIdentity,
+	pub controller: Arc<RwLock<ActuatedController>>,
+	pub phase: usize,
+}
+
+#[async_trait]
+impl Signal for ActuatedLight {
+	fn identity(&self) -> &SignalIdentity {
+		&self.signal_identity
+	}
+
+	fn identity_mut(&mut self) -> &mut SignalIdentity {
+		&mut self.signal_identity
+	}
+
+	fn activate(&mut self,
+		_allocation: &NetworkAllocation,
+		_vehicle: &Vehicle
+	) {}
+
+	fn instruct(&mut self,
+		allocation: &NetworkAllocation,
+		vehicle: &Vehicle
+	) -> InstructResult {
+		let (state, remaining) = self.controller.read().unwrap().state(self.phase);
+		light_instruct(allocation, vehicle, &self.signal_identity, state, remaining)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, vehicle::{VehicleData, VehicleIdentity}, signal::LightState, NetworkAllocation};
+
+	use super::{ActuatedController, ActuatedPhase, Detector, PhaseTermination};
+
+	fn phase(lane: u32) -> ActuatedPhase {
+		ActuatedPhase {
+			detectors: vec![Detector {
+				lane,
+				distance: 140.0,
+				length: 20.0
+			}],
+			..Default::default()
+		}
+	}
+
+	fn occupy(allocation: &NetworkAllocation, lane: u32) {
+		allocation.lane(lane).write().unwrap().insert_vehicle(VehicleData {
+			identity: VehicleIdentity {
+				sub: lane,
+				..Default::default()
+			},
+			length: 10.0,
+			distance: 135.0,
+			..Default::default()
+		});
+	}
+
+	fn run(controller: &mut ActuatedController, allocation: &NetworkAllocation, seconds: f32) {
+		for _ in 0..(seconds / 0.5) as usize {
+			controller.tick(allocation, 0.5);
+		}
+	}
+
+	#[test]
+	fn rests_in_green_without_calls() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let mut controller = ActuatedController::new(vec![phase(1), phase(2)]);
+		occupy(allocation, 1);
+		run(&mut controller, allocation, 100.0);
+		assert_eq!(controller.state(0), (LightState::Green, f32::INFINITY));
+		assert_eq!(controller.state(1).0, LightState::Red);
+		assert!(controller.last_termination.is_none());
+	}
+
+	#[test]
+	fn max_green_counts_from_the_first_call() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let mut controller = ActuatedController::new(vec![phase(1), phase(2)]);
+		occupy(allocation, 1);
+		run(&mut controller, allocation, 100.0);
+		occupy(allocation, 2);
+		run(&mut controller, allocation, 40.0);
+		assert_eq!(controller.state(0).0, LightState::Green);
+		assert!(controller.called(1));
+		run(&mut controller, allocation, 11.0);
+		assert_eq!(controller.state(0).0, LightState::Yellow);
+		assert_eq!(controller.last_termination, Some(PhaseTermination::MaxOut));
+		run(&mut controller, allocation, 6.0);
+		assert_eq!(controller.state(1).0, LightState::Green);
+	}
+
+	#[test]
+	fn gap_out_is_checked_before_max_out() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let mut controller = ActuatedController::new(vec![
+			ActuatedPhase {
+				max_green: 5.0,
+				..phase(1)
+			},
+			phase(2)
+		]);
+		occupy(allocation, 2);
+		run(&mut controller, allocation, 10.0);
+		assert_eq!(controller.last_termination, Some(PhaseTermination::GapOut));
+	}
+
+	#[test]
+	fn no_phases_show_green() {
+		let controller = ActuatedController::new(Vec::new());
+		assert_eq!(controller.state(0), (LightState::Green, f32::INFINITY));
+	}
+}
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet, BinaryHeap};
+
+use rand::Rng;
+
+use super::{navigation::Navigation, NetworkAllocation, hierarchy::QueueEntry};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChoiceModel {
+	// Every alternative is equally likely.
+	Uniform,
+	// Probability proportional to exp(-theta * cost), costs in distance units.
+	Logit { theta: f64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RouteChoice {
+	// Number of alternative band paths to choose from.
+	pub alternatives: usize,
+	pub model: ChoiceModel,
+	// Share the route being driven may cost more than the best alternative
+	// before a reroute draws a new one.
+	pub switch_threshold: f64,
+}
+
+impl Default for RouteChoice {
+	fn default() -> Self {
+		Self {
+			alternatives: 3,
+			model: ChoiceModel::Logit { theta: 0.01 },
+			switch_threshold: 0.2
+		}
+	}
+}
+
+// Band paths found by k_shortest_paths, by source band, target lane and
+// whether travel times were used. Their costs are taken again on every use.
+// Cleared by NetworkAllocation::invalidate_hierarchy.
+#[derive(Debug, Default)]
+pub struct AlternativesCache {
+	paths: HashMap<(u32, u32, bool), Vec<Vec<u32>>>,
+}
+
+impl AlternativesCache {
+	pub fn clear(&mut self) {
+		self.paths.clear();
+	}
+}
+
+impl RouteChoice {
+	// Picks one of the navigation's k shortest band paths from src_band with
+	// the network rng. Paths start with src_band. current, the rest of the
+	// route being driven from src_band, is kept while it is open and within
+	// the switch threshold of the best alternative.
+	pub fn choose(
+		&self,
+		allocation: &NetworkAllocation,
+		navigation: &Navigation,
+		src_band: u32,
+		current: &[u32]
+	) -> Option<Vec<u32>> {
+		let mut paths = alternatives(allocation, navigation, src_band, self.alternatives);
+		if paths.is_empty() {
+			return None;
+		}
+		let min_cost = paths.iter().fold(f64::INFINITY, |a, b| a.min(b.1));
+
+		// CURRENT
+
+		let target_band = navigation.target_identity.band;
+		if current.len() >= 2 && current[0] == src_band && current[current.len() - 1] == target_band {
+			if let Some(cost) = path_cost(allocation, navigation, current) {
+				if cost <= min_cost * (1.0 + self.switch_threshold) {
+					return Some(current.to_vec());
+				}
+			}
+		}
+
+		// DRAW
+
+		let weights: Vec<f64> = paths.iter().map(
+			|x|
+			match self.model {
+				ChoiceModel::Uniform => 1.0,
+				ChoiceModel::Logit { theta } => (-theta * (x.1 - min_cost)).exp()
+			}
+		).collect();
+		let mut pick = allocation.rng.write().unwrap().0.gen::<f64>() * weights.iter().sum::<f64>();
+		for (i, weight) in weights.iter().enumerate() {
+			if pick < *weight {
+				return Some(paths.swap_remove(i).0);
+			}
+			pick -= weight;
+		}
+		Some(paths.pop().unwrap().0)
+	}
+}
+
+// The cached alternatives that are still open with their current costs. The
+// paths are searched again when none is left.
+fn alternatives(
+	allocation: &NetworkAllocation,
+	navigation: &Navigation,
+	src_band: u32,
+	k: usize
+) -> Vec<(Vec<u32>, f64)> {
+	let key = (src_band, navigation.target_identity.lane, navigation.informed);
+	let cached: Vec<(Vec<u32>, f64)> = match allocation.alternatives_cache.read().unwrap().paths.get(&key) {
+		Some(paths) => paths.iter().filter_map(
+			|x|
+			path_cost(allocation, navigation, x).map(|y| (x.clone(), y))
+		).collect(),
+		None => Vec::new()
+	};
+	if !cached.is_empty() {
+		return cached;
+	}
+	let paths = k_shortest_paths(allocation, navigation, src_band, k);
+	allocation.alternatives_cache.write().unwrap().paths.insert(key, paths.iter().map(
+		|x|
+		x.0.clone()
+	).collect());
+	paths
+}
+
+// Cost of driving the band path now, None when one of its transitions is
+// closed or restricted.
+fn path_cost(
+	allocation: &NetworkAllocation,
+	navigation: &Navigation,
+	path: &[u32]
+) -> Option<f64> {
+	let time = allocation.time();
+	let mut result: f64 = 0.0;
+	for pair in path.windows(2) {
+		result += successors(allocation, navigation, pair[0], time).iter().find(
+			|x|
+			x.0 == pair[1]
+		)?.1;
+	}
+	Some(result)
+}
+
+// Yen's algorithm over the band graph with the costs, restrictions and
+// closures of Navigation::renavigate.
+pub fn k_shortest_paths(
+	allocation: &NetworkAllocation,
+	navigation: &Navigation,
+	src_band: u32,
+	k: usize
+) -> Vec<(Vec<u32>, f64)> {
+	let time = allocation.time();
+	let no_bands: HashSet<u32> = HashSet::new();
+	let no_edges: HashSet<(u32, u32)> = HashSet::new();
+	let mut result: Vec<(Vec<u32>, f64)> = Vec::new();
+	let mut candidates: Vec<(Vec<u32>, f64)> = Vec::new();
+	match shortest_path(allocation, navigation, src_band, &no_bands, &no_edges, time) {
+		Some(x) => result.push(x),
+		None => { return result; }
+	}
+	while result.len() < k {
+		let previous = result.last().unwrap().0.clone();
+		for i in 0..(previous.len() - 1) {
+
+			// SPUR
+
+			let root = &previous[..(i + 1)];
+			let mut banned_edges: HashSet<(u32, u32)> = HashSet::new();
+			for path in result.iter() {
+				if path.0.len() > i + 1 && &path.0[..(i + 1)] == root {
+					banned_edges.insert((path.0[i], path.0[i + 1]));
+				}
+			}
+			let banned_bands: HashSet<u32> = root[..i].iter().copied().collect();
+			let spur = match shortest_path(allocation, navigation, root[i], &banned_bands, &banned_edges, time) {
+				Some(x) => x,
+				None => { continue; }
+			};
+			let mut root_cost: f64 = 0.0;
+			for pair in root.windows(2) {
+				root_cost += successors(allocation, navigation, pair[0], time).iter().find(
+					|x|
+					x.0 == pair[1]
+				).map_or(0.0, |x| x.1);
+			}
+			let mut path: Vec<u32> = root[..i].to_vec();
+			path.extend(spur.0);
+			if !result.iter().any(|x| x.0 == path) && !candidates.iter().any(|x| x.0 == path) {
+				candidates.push((path, root_cost + spur.1));
+			}
+		}
+
+		// NEXT SHORTEST
+
+		let best = match candidates.iter().enumerate().min_by(
+			|a, b|
+			a.1.1.partial_cmp(&b.1.1).expect("invalid distance")
+		) {
+			Some(x) => x.0,
+			None => { break; }
+		};
+		result.push(candidates.swap_remove(best));
+	}
+	result
+}
+
+fn successors(
+	allocation: &NetworkAllocation,
+	navigation: &Navigation,
+	band: u32,
+	time: f64
+) -> Vec<(u32, f64)> {
+	let mut result: Vec<(u32, f64)> = Vec::new();
+	let dst_clip = allocation.band(band).read().unwrap().dst_clip;
+	let c_clip_fw = allocation.clip(dst_clip);
+	let ra_clip_fw = c_clip_fw.read().unwrap();
+	for i in ra_clip_fw.fw_bands.iter() {
+		let c_band_fw = allocation.band(*i);
+		let ra_band_fw = c_band_fw.read().unwrap();
+		if ra_band_fw.closed(time) || !ra_clip_fw.transition_allowed(band, *i, time) {
+			continue;
+		}
+		let length = allocation.lane(
+			ra_clip_fw.lanes_fixed[ra_band_fw.src_min as usize].fw[0]
+		).read().unwrap().length;
+		let cost: f32 = match navigation.informed {
+			true => ra_band_fw.travel_cost(length),
+			false => length
+		};
+		result.push((*i, cost as f64));
+	}
+	result
+}
+
+// Dijkstra from src_band to the navigation's target band. The target band is
+// only entered from bands that can reach the target lane.
+fn shortest_path(
+	allocation: &NetworkAllocation,
+	navigation: &Navigation,
+	src_band: u32,
+	banned_bands: &HashSet<u32>,
+	banned_edges: &HashSet<(u32, u32)>,
+	time: f64
+) -> Option<(Vec<u32>, f64)> {
+	let target_band = navigation.target_identity.band;
+	let mut band_g: HashMap<u32, f64> = HashMap::new();
+	let mut preceding: HashMap<u32, u32> = HashMap::new();
+	let mut open: BinaryHeap<QueueEntry> = BinaryHeap::new();
+	let mut closed: HashSet<u32> = HashSet::new();
+	band_g.insert(src_band, 0.0);
+	open.push(QueueEntry { cost: 0.0, node: src_band as usize });
+	while let Some(entry) = open.pop() {
+		let band_min = entry.node as u32;
+		if !closed.insert(band_min) {
+			continue;
+		}
+		if band_min == target_band {
+			let mut path: Vec<u32> = vec![band_min];
+			let mut current = band_min;
+			while let Some(x) = preceding.get(&current) {
+				path.insert(0, *x);
+				current = *x;
+			}
+			return Some((path, entry.cost));
+		}
+		for (band_fw, cost) in successors(allocation, navigation, band_min, time) {
+			if closed.contains(&band_fw) || banned_bands.contains(&band_fw) ||
+				banned_edges.contains(&(band_min, band_fw)) {
+				continue;
+			}
+			if band_fw == target_band && !navigation.target_reachable(allocation, band_min) {
+				continue;
+			}
+			let pos_g_cost = entry.cost + cost;
+			if pos_g_cost < band_g.get(&band_fw).copied().unwrap_or(f64::INFINITY) {
+				band_g.insert(band_fw, pos_g_cost);
+				preceding.insert(band_fw, band_min);
+				open.push(QueueEntry { cost: pos_g_cost, node: band_fw as usize });
+			}
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, navigation::Navigation, lane::LaneIdentity, restriction::TimeWindow};
+
+	use super::{k_shortest_paths, path_cost, ChoiceModel, RouteChoice};
+
+	fn navigation(target: LaneIdentity) -> Navigation {
+		Navigation {
+			target_identity: target,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn yen_finds_both_routes_in_cost_order() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let navigation = navigation(lanes[12]);
+		let paths = k_shortest_paths(allocation, &navigation, 1, 5);
+		assert_eq!(paths.len(), 2);
+		assert!(paths.iter().any(|x| x.0 == vec![1, 2, 3, 5, 6, 9]));
+		assert!(paths.iter().any(|x| x.0 == vec![1, 4, 5, 6, 9]));
+		assert!(paths[0].1 <= paths[1].1);
+		for (path, cost) in paths.iter() {
+			assert!((path_cost(allocation, &navigation, path).unwrap() - cost).abs() < 1e-3);
+		}
+	}
+
+	#[test]
+	fn logit_prefers_cheaper_routes() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let navigation = navigation(lanes[12]);
+		let paths = k_shortest_paths(allocation, &navigation, 1, 2);
+		let sharp = RouteChoice {
+			model: ChoiceModel::Logit { theta: 10.0 },
+			..Default::default()
+		};
+		for _ in 0..20 {
+			assert_eq!(sharp.choose(allocation, &navigation, 1, &[]), Some(paths[0].0.clone()));
+		}
+		let uniform = RouteChoice {
+			model: ChoiceModel::Uniform,
+			..Default::default()
+		};
+		let picks: Vec<Vec<u32>> = (0..50).map(
+			|_|
+			uniform.choose(allocation, &navigation, 1, &[]).unwrap()
+		).collect();
+		assert!(picks.contains(&paths[0].0) && picks.contains(&paths[1].0));
+	}
+
+	#[test]
+	fn current_route_is_kept_until_blocked() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let navigation = navigation(lanes[12]);
+		let paths = k_shortest_paths(allocation, &navigation, 1, 2);
+		let costly = paths[1].0.clone();
+		let choice = RouteChoice {
+			model: ChoiceModel::Logit { theta: 10.0 },
+			switch_threshold: 1.0,
+			..Default::default()
+		};
+		assert_eq!(choice.choose(allocation, &navigation, 1, &costly), Some(costly.clone()));
+		let strict = RouteChoice {
+			switch_threshold: 0.0,
+			..choice
+		};
+		assert_eq!(strict.choose(allocation, &navigation, 1, &costly), Some(paths[0].0.clone()));
+
+		// The cached paths are checked against closures.
+		allocation.band(costly[1]).write().unwrap().closures.push(TimeWindow::always());
+		assert_eq!(choice.choose(allocation, &navigation, 1, &costly), Some(paths[0].0.clone()));
+		assert_eq!(allocation.alternatives_cache.read().unwrap().paths.len(), 1);
+	}
+}
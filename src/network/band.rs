@@ -2,7 +2,7 @@ use std::sync::{atomic::Ordering, Arc, RwLock};
 
 use crate::network_allocation;
 
-use super::{Network};
+use super::{Network, congestion::TRAVEL_TIME_SMOOTHING, restriction::TimeWindow, LANE_SPEED};
 
 #[derive(Default, Debug, Clone)]
 pub struct BandIdentity {
@@ -21,6 +21,13 @@ pub struct Band {
 	pub dst_max: u8,
 
 	pub empty: bool,
+
+	// Smoothed seconds vehicles needed to traverse the band.
+	pub travel_time: f32,
+	pub travel_samples: u32,
+
+	// Windows in which the band can not be entered.
+	pub closures: Vec<TimeWindow>,
 }
 
 impl Band {
@@ -52,10 +59,82 @@ impl Band {
 				u8::MAX,
 				dst_max:
 				u8::MAX,
-				empty: true
+				empty: true,
+				travel_time: 0.0,
+				travel_samples: 0,
+				closures: Vec::new()
 			})
 		));
+		allocation.invalidate_hierarchy();
 
 		id
 	}
-}
\ No newline at end of file
+
+	pub fn record_travel_time(&mut self, seconds: f32) {
+		if self.travel_samples == 0 {
+			self.travel_time = seconds;
+		} else {
+			self.travel_time += (seconds - self.travel_time) * TRAVEL_TIME_SMOOTHING;
+		}
+		self.travel_samples += 1;
+	}
+
+	// Routing cost in distance units. The travel time is scaled by the free
+	// flow speed so it never drops below the length, which keeps the
+	// navigation heuristic admissible.
+	pub fn travel_cost(&self, length: f32) -> f32 {
+		if self.travel_samples == 0 {
+			return length;
+		}
+		(self.travel_time * LANE_SPEED).max(length)
+	}
+
+	pub fn closed(&self, time: f64) -> bool {
+		self.closures.iter().any(|x| x.contains(time))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, navigation::Navigation};
+
+	#[test]
+	fn travel_time_is_smoothed() {
+		let (network, _) = testing::network();
+		let c_band = network.allocation.band(1);
+		let mut wa_band = c_band.write().unwrap();
+		assert_eq!(wa_band.travel_cost(150.0), 150.0);
+		wa_band.record_travel_time(10.0);
+		assert_eq!(wa_band.travel_time, 10.0);
+		wa_band.record_travel_time(20.0);
+		assert!((wa_band.travel_time - 12.0).abs() < 1e-4);
+		// Never cheaper than the length.
+		assert_eq!(wa_band.travel_cost(5_000.0), 5_000.0);
+		assert!((wa_band.travel_cost(150.0) - 1_200.0).abs() < 1e-2);
+	}
+
+	#[test]
+	fn informed_route_avoids_congestion() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let route = |informed: bool| {
+			let mut navigation = Navigation {
+				target_identity: lanes[12],
+				informed,
+				..Default::default()
+			};
+			assert!(navigation.renavigate(allocation, lanes[0]));
+			navigation.nav.iter().map(
+				|x|
+				x.band
+			).collect::<Vec<u32>>()
+		};
+
+		// band_b and band_c against band_d.
+		allocation.band(2).write().unwrap().record_travel_time(100.0);
+		assert_eq!(route(true), vec![4, 5, 6, 9]);
+		allocation.band(2).write().unwrap().travel_samples = 0;
+		allocation.band(4).write().unwrap().record_travel_time(100.0);
+		assert_eq!(route(true), vec![2, 3, 5, 6, 9]);
+	}
+}
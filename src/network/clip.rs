@@ -1,8 +1,8 @@
-use std::sync::{atomic::Ordering, Arc, RwLock};
+use std::{collections::HashMap, sync::{atomic::Ordering, Arc, RwLock}};
 
 use crate::network_allocation;
 
-use super::{Network};
+use super::{Network, restriction::TurnRestriction, merge::MergeState};
 
 #[derive(Debug, Default, Clone)]
 pub struct Fixed {
@@ -17,6 +17,9 @@ pub struct Clip {
 	// Fixed size of how long the clip is. Forward then back.
 	pub lanes_fixed: Vec<Fixed>,
 	pub fw_bands: Vec<u32>,
+	pub restrictions: Vec<TurnRestriction>,
+	// Zipper state of the lanes_fixed entries lanes merge at.
+	pub merge_states: HashMap<u8, MergeState>,
 }
 
 impl Clip {
@@ -40,7 +43,31 @@ impl Clip {
 		allocation.clips.write().unwrap().insert(id, Arc::new(
 			RwLock::new(Self::default())
 		));
+		allocation.invalidate_hierarchy();
 
 		id
 	}
+
+	// Position in lanes_fixed of a lane that starts at this clip.
+	pub fn fw_index(&self, lane: u32) -> Option<u8> {
+		self.lanes_fixed.iter().position(
+			|x|
+			x.fw[..x.fw_count as usize].contains(&lane)
+		).map(|x| x as u8)
+	}
+
+	// Position in lanes_fixed of a lane that ends at this clip.
+	pub fn bw_index(&self, lane: u32) -> Option<u8> {
+		self.lanes_fixed.iter().position(
+			|x|
+			x.bw[..x.bw_count as usize].contains(&lane)
+		).map(|x| x as u8)
+	}
+
+	pub fn transition_allowed(&self, from_band: u32, to_band: u32, time: f64) -> bool {
+		!self.restrictions.iter().any(
+			|x|
+			x.from_band == from_band && x.to_band == to_band && x.window.contains(time)
+		)
+	}
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+
+use super::{vehicle::{VehicleData, VehicleIdentity}, event::NetworkEvent, NetworkAllocation};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionConfig {
+	// Colliding vehicles stand still as an incident, blocking their lane.
+	pub incidents: bool,
+	// Seconds an incident blocks its lane.
+	pub incident_duration: f32,
+	// Distance before a merge point in which vehicles on converging lanes
+	// can hit each other.
+	pub merge_zone: f32,
+}
+
+impl Default for CollisionConfig {
+	fn default() -> Self {
+		Self {
+			incidents: false,
+			incident_duration: 120.0,
+			merge_zone: 10.0
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Collision {
+	// Vehicle in front, then the one that ran into it.
+	pub first: VehicleIdentity,
+	pub second: VehicleIdentity,
+	// Lane the overlap was found on.
+	pub lane: u32,
+	pub overlap: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Incident {
+	pub vehicles: [VehicleIdentity; 2],
+	pub lane: u32,
+	// Seconds left until the lane is clear.
+	pub remaining: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct CollisionState {
+	// Vehicle pairs overlapping at the last pass, smaller sub first. A pair
+	// is only reported again once it separated.
+	overlaps: HashSet<(u32, u32)>,
+	pub incidents: Vec<Incident>,
+}
+
+// Stretch a vehicle occupies, its front measured along one lane.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+	vehicle: VehicleData,
+	front: f32,
+	// Lane the vehicle is on.
+	source: u32,
+}
+
+// Overlapping vehicle pairs of all lanes. A vehicle is checked against the
+// vehicles on its lane, the rear of vehicles that just left onto a forward
+// lane and, close to a merge point, the vehicles on the converging lanes.
+pub fn detect_collisions(
+	allocation: &NetworkAllocation
+) -> Vec<Collision> {
+	let mut result: Vec<Collision> = Vec::new();
+	let mut found: HashSet<(u32, u32)> = HashSet::new();
+	let ra_lanes = allocation.lanes.read().unwrap();
+	let mut lane_ids: Vec<u32> = ra_lanes.keys().copied().collect();
+	lane_ids.sort();
+
+	// ALONG LANES
+
+	for lane_id in lane_ids.iter() {
+		let ra_lane = ra_lanes[lane_id].read().unwrap();
+		let mut spans: Vec<Span> = ra_lane.vehicles.iter().map(
+			|x|
+			Span { vehicle: *x, front: x.distance, source: *lane_id }
+		).collect();
+		for fw_lane in ra_lane.fw_lanes.iter() {
+			let ra_fw_lane = ra_lanes[&fw_lane.lane].read().unwrap();
+			for vehicle in ra_fw_lane.vehicles.iter().filter(|x| x.distance < x.length) {
+				spans.push(Span {
+					vehicle: *vehicle,
+					front: ra_lane.length + vehicle.distance,
+					source: fw_lane.lane
+				});
+			}
+		}
+		overlaps(&mut spans, *lane_id, false, &mut found, &mut result);
+	}
+	drop(ra_lanes);
+
+	// MERGES
+
+	let config = *allocation.collision_config.read().unwrap();
+	let ra_clips = allocation.clips.read().unwrap();
+	let mut clip_ids: Vec<u32> = ra_clips.keys().copied().collect();
+	clip_ids.sort();
+	for clip_id in clip_ids.iter() {
+		let ra_clip = ra_clips[clip_id].read().unwrap();
+		for lane_fixed in ra_clip.lanes_fixed.iter().filter(|x| x.bw_count >= 2) {
+			// Fronts are negative distances to the merge point.
+			let mut spans: Vec<Span> = Vec::new();
+			for i in 0..lane_fixed.bw_count {
+				let lane = lane_fixed.bw[i as usize];
+				let c_lane = allocation.lane(lane);
+				let ra_lane = c_lane.read().unwrap();
+				for vehicle in ra_lane.vehicles.iter() {
+					let remaining = ra_lane.length - vehicle.distance;
+					if remaining <= config.merge_zone {
+						spans.push(Span { vehicle: *vehicle, front: -remaining, source: lane });
+					}
+				}
+			}
+			overlaps(&mut spans, lane_fixed.fw[0], true, &mut found, &mut result);
+		}
+	}
+	result
+}
+
+fn overlaps(
+	spans: &mut [Span],
+	lane: u32,
+	across_lanes: bool,
+	found: &mut HashSet<(u32, u32)>,
+	result: &mut Vec<Collision>
+) {
+	spans.sort_by(
+		|a, b|
+		a.front.partial_cmp(&b.front).expect("invalid distance")
+	);
+	let max_length = spans.iter().fold(0.0, |a: f32, x| a.max(x.vehicle.length));
+	for i in 0..spans.len() {
+		let behind = spans[i];
+		for ahead in spans.iter().skip(i + 1) {
+			if ahead.front - max_length >= behind.front {
+				break;
+			}
+			let overlap = behind.front - (ahead.front - ahead.vehicle.length);
+			if overlap <= 0.0 ||
+				ahead.vehicle.identity.sub == behind.vehicle.identity.sub ||
+				(across_lanes && ahead.source == behind.source) {
+				continue;
+			}
+			let key = pair_key(ahead.vehicle.identity.sub, behind.vehicle.identity.sub);
+			if !found.insert(key) {
+				continue;
+			}
+			result.push(Collision {
+				first: ahead.vehicle.identity,
+				second: behind.vehicle.identity,
+				lane,
+				overlap
+			});
+		}
+	}
+}
+
+fn pair_key(a: u32, b: u32) -> (u32, u32) {
+	(a.min(b), a.max(b))
+}
+
+// Call once per simulation step after the vehicles ticked. Reports new
+// collisions as events, starts incidents for them when enabled and clears
+// incidents that ran out.
+pub fn tick_collisions(
+	allocation: &NetworkAllocation,
+	delta_time: f32
+) {
+	let config = *allocation.collision_config.read().unwrap();
+	let collisions = detect_collisions(allocation);
+	let mut wa_state = allocation.collisions.write().unwrap();
+
+	// CLEAR INCIDENTS
+
+	let mut cleared: Vec<Incident> = Vec::new();
+	wa_state.incidents.retain_mut(
+		|x|
+		{
+			x.remaining -= delta_time;
+			if x.remaining <= 0.0 {
+				cleared.push(*x);
+				return false;
+			}
+			true
+		}
+	);
+	for incident in cleared {
+		allocation.push_event(NetworkEvent::IncidentCleared {
+			vehicles: incident.vehicles,
+			lane: incident.lane
+		});
+	}
+
+	// REPORT COLLISIONS
+
+	let mut overlaps: HashSet<(u32, u32)> = HashSet::new();
+	for collision in collisions {
+		let key = pair_key(collision.first.sub, collision.second.sub);
+		overlaps.insert(key);
+		if wa_state.overlaps.contains(&key) {
+			continue;
+		}
+		allocation.push_event(NetworkEvent::Collision {
+			first: collision.first,
+			second: collision.second,
+			lane: collision.lane,
+			overlap: collision.overlap
+		});
+		if !config.incidents {
+			continue;
+		}
+		for identity in [collision.first, collision.second] {
+			hold_vehicle(allocation, identity, config.incident_duration);
+		}
+		let incident = Incident {
+			vehicles: [collision.first, collision.second],
+			lane: collision.lane,
+			remaining: config.incident_duration
+		};
+		wa_state.incidents.push(incident);
+		allocation.push_event(NetworkEvent::IncidentStarted {
+			vehicles: incident.vehicles,
+			lane: incident.lane,
+			duration: config.incident_duration
+		});
+	}
+	wa_state.overlaps = overlaps;
+}
+
+fn hold_vehicle(
+	allocation: &NetworkAllocation,
+	identity: VehicleIdentity,
+	seconds: f32
+) {
+	let vehicle_batch = match allocation.vehicle_batch_of(identity.sub) {
+		Some(x) => x,
+		None => { return; }
+	};
+	let mut wa_vehicle_batch = vehicle_batch.write().unwrap();
+	if let Some(vehicle) = wa_vehicle_batch.vehicle_mut(identity.sub) {
+		vehicle.hold(allocation, seconds);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, vehicle::{Vehicle, VehicleData, VehicleIdentity}, event::NetworkEvent, Network, NetworkAllocation};
+
+	use super::{detect_collisions, tick_collisions};
+
+	fn place(allocation: &NetworkAllocation, sub: u32, lane: u32, distance: f32) {
+		allocation.lane(lane).write().unwrap().insert_vehicle(VehicleData {
+			identity: VehicleIdentity {
+				sub,
+				..Default::default()
+			},
+			length: 10.0,
+			distance,
+			..Default::default()
+		});
+	}
+
+	fn pairs(allocation: &NetworkAllocation) -> Vec<(u32, u32)> {
+		detect_collisions(allocation).iter().map(
+			|x|
+			(x.first.sub, x.second.sub)
+		).collect()
+	}
+
+	#[test]
+	fn overlaps_on_a_lane() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		place(allocation, 1, 1, 50.0);
+		place(allocation, 2, 1, 45.0);
+		place(allocation, 3, 1, 30.0);
+		assert_eq!(pairs(allocation), vec![(1, 2)]);
+	}
+
+	#[test]
+	fn overlaps_across_a_lane_end() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		// Its rear is still on lane_a.
+		place(allocation, 1, 3, 4.0);
+		place(allocation, 2, 1, 148.0);
+		assert_eq!(pairs(allocation), vec![(1, 2)]);
+	}
+
+	#[test]
+	fn overlaps_at_a_merge() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let length_a = allocation.lane(10).read().unwrap().length;
+		let length_b = allocation.lane(11).read().unwrap().length;
+		place(allocation, 1, 10, length_a - 3.0);
+		place(allocation, 2, 11, length_b - 5.0);
+		// Side by side, but far from the merge point.
+		place(allocation, 3, 10, 50.0);
+		place(allocation, 4, 11, 50.0);
+		assert_eq!(pairs(allocation), vec![(1, 2)]);
+	}
+
+	#[test]
+	fn collisions_are_reported_once() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		allocation.collision_config.write().unwrap().incidents = true;
+		let a = Vehicle::new(&network, lanes[0], lanes[6]);
+		let b = Vehicle::new(&network, lanes[0], lanes[6]);
+		tick_collisions(allocation, 0.1);
+		tick_collisions(allocation, 0.1);
+		let events = allocation.drain_events();
+		let collisions = events.iter().filter(|x| matches!(x, NetworkEvent::Collision { .. })).count();
+		let incidents = events.iter().filter(|x| matches!(x, NetworkEvent::IncidentStarted { .. })).count();
+		assert_eq!((collisions, incidents), (1, 1));
+		// Held where they collided.
+		for _ in 0..20 {
+			Network::tick(&network, 0.05);
+		}
+		for id in [a, b] {
+			assert_eq!(testing::with_vehicle(&network, id.sub, |x| x.data.distance), Some(0.0));
+		}
+	}
+}
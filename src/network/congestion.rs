@@ -0,0 +1,27 @@
+// Weight of a new travel time sample against the band's current estimate.
+pub const TRAVEL_TIME_SMOOTHING: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RerouteConfig {
+	// Fraction of spawned vehicles that route with band travel times and
+	// reroute while driving. The rest keep their spawn route.
+	pub informed_fraction: f32,
+	// Seconds between periodic reroutes of an informed vehicle.
+	pub period: f32,
+	// A vehicle is delayed once it spent this many times the free flow
+	// time on its active lane.
+	pub delay_factor: f32,
+	// Minimum seconds between two delay triggered reroutes.
+	pub cooldown: f32,
+}
+
+impl Default for RerouteConfig {
+	fn default() -> Self {
+		Self {
+			informed_fraction: 0.0,
+			period: 30.0,
+			delay_factor: 2.0,
+			cooldown: 5.0,
+		}
+	}
+}
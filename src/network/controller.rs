@@ -0,0 +1,454 @@
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use super::{signal::{Signal, SignalIdentity, InstructResult, LightState, light_instruct}, vehicle::Vehicle, NetworkAllocation};
+
+// Lane signals that always show the same state.
+#[derive(Debug, Clone)]
+pub struct SignalGroup {
+	// Ids of the group's signals.
+	pub signals: Vec<u32>,
+	pub yellow: f32,
+}
+
+// Groups green together and for how long.
+#[derive(Debug, Clone)]
+pub struct Stage {
+	pub groups: Vec<usize>,
+	pub green: f32,
+}
+
+// Fixed time control of an intersection. Stages are served in order, no two
+// conflicting groups are green at once. Between two stages the groups that
+// end show yellow, then red, and a group that starts waits for the
+// intergreen time to every conflicting group that ended.
+#[derive(Debug, Default)]
+pub struct SignalController {
+	groups: Vec<SignalGroup>,
+	stages: Vec<Stage>,
+	// Common cycle of a coordinated corridor. The greens are stretched or
+	// shrunk to it, None runs the stages as given.
+	cycle: Option<f32>,
+	// Seconds into the cycle at simulation time 0.
+	pub offset: f32,
+	// conflicts[a][b] when groups a and b may not be green together.
+	conflicts: Vec<Vec<bool>>,
+	// intergreen[a][b] seconds from the end of a's green to the start of b's.
+	intergreen: Vec<Vec<f32>>,
+	// Built from the above by refresh whenever they change, state is asked
+	// for every vehicle every tick.
+	timings: Vec<(f32, f32)>,
+	segments: Vec<Vec<(LightState, f32)>>,
+	// The transitions alone take the whole common cycle.
+	overrun: bool,
+}
+
+impl SignalController {
+	pub fn add_group(&mut self, yellow: f32) -> usize {
+		let idx = self.groups.len();
+		self.groups.push(SignalGroup {
+			signals: Vec::new(),
+			yellow
+		});
+		for row in self.conflicts.iter_mut() {
+			row.push(false);
+		}
+		for row in self.intergreen.iter_mut() {
+			row.push(0.0);
+		}
+		self.conflicts.push(vec![false; idx + 1]);
+		self.intergreen.push(vec![0.0; idx + 1]);
+		self.refresh();
+		idx
+	}
+
+	// Marks a and b as conflicting. a_to_b is the intergreen from the end of
+	// a's green to the start of b's, b_to_a the other way around. False when
+	// a or b is not a group, they are the same group or a stage already has
+	// both green, nothing changes then.
+	pub fn set_conflict(
+		&mut self,
+		a: usize,
+		b: usize,
+		a_to_b: f32,
+		b_to_a: f32
+	) -> bool {
+		if a == b || a >= self.groups.len() || b >= self.groups.len() {
+			return false;
+		}
+		if self.stages.iter().any(
+			|x|
+			x.groups.contains(&a) && x.groups.contains(&b)
+		) {
+			return false;
+		}
+		self.conflicts[a][b] = true;
+		self.conflicts[b][a] = true;
+		self.intergreen[a][b] = a_to_b;
+		self.intergreen[b][a] = b_to_a;
+		self.refresh();
+		true
+	}
+
+	// False for groups that don't exist.
+	pub fn conflicts(&self, a: usize, b: usize) -> bool {
+		self.conflicts.get(a)
+			.and_then(
+				|x|
+				x.get(b)
+			)
+			.copied()
+			.unwrap_or(false)
+	}
+
+	// None when a group doesn't exist or two of the groups conflict.
+	pub fn add_stage(&mut self, groups: Vec<usize>, green: f32) -> Option<usize> {
+		if groups.iter().any(|x| *x >= self.groups.len()) {
+			return None;
+		}
+		for (i, a) in groups.iter().enumerate() {
+			if groups.iter().skip(i + 1).any(|b| self.conflicts[*a][*b]) {
+				return None;
+			}
+		}
+		self.stages.push(Stage {
+			groups,
+			green
+		});
+		self.refresh();
+		Some(self.stages.len() - 1)
+	}
+
+	pub fn groups(&self) -> &[SignalGroup] {
+		&self.groups
+	}
+
+	pub fn stages(&self) -> &[Stage] {
+		&self.stages
+	}
+
+	pub fn cycle(&self) -> Option<f32> {
+		self.cycle
+	}
+
+	pub fn set_cycle(&mut self, cycle: Option<f32>) {
+		self.cycle = cycle;
+		self.refresh();
+	}
+
+	// False when the transitions alone take the whole common cycle. The
+	// stages then run as given, on a cycle of their own.
+	pub fn fits_cycle(&self) -> bool {
+		!self.overrun
+	}
+
+	// Creates a light showing the group's state on the signal's lane.
+	pub fn attach(
+		controller: &Arc<RwLock<SignalController>>,
+		allocation: &NetworkAllocation,
+		group: usize,
+		signal_identity: SignalIdentity
+	) {
+		controller.write().unwrap().groups[group].signals.push(signal_identity.id);
+		let light = GroupLight {
+			signal_identity,
+			controller: controller.clone(),
+			group
+		};
+		let c_lane = allocation.lane(signal_identity.lane);
+		c_lane.write().unwrap().signals.push(Arc::new(light));
+	}
+
+	// Seconds between the end of stage i's green and the start of the next
+	// stage's.
+	fn transition(&self, i: usize) -> f32 {
+		let (ending, starting) = self.changes(i);
+		let mut result: f32 = 0.0;
+		for a in ending.iter() {
+			result = result.max(self.groups[*a].yellow);
+			for b in starting.iter() {
+				if self.conflicts[*a][*b] {
+					result = result.max(self.intergreen[*a][*b]);
+				}
+			}
+		}
+		result
+	}
+
+	// Groups that end after stage i and groups that start with the next.
+	fn changes(&self, i: usize) -> (Vec<usize>, Vec<usize>) {
+		let current = &self.stages[i].groups;
+		let next = &self.stages[(i + 1) % self.stages.len()].groups;
+		(
+			current.iter().filter(|x| !next.contains(x)).copied().collect(),
+			next.iter().filter(|x| !current.contains(x)).copied().collect()
+		)
+	}
+
+	// Rebuilds the timings and segments after anything they follow from
+	// changed.
+	fn refresh(&mut self) {
+		let transitions: Vec<f32> = (0..self.stages.len()).map(
+			|x|
+			self.transition(x)
+		).collect();
+		let green_sum: f32 = self.stages.iter().map(|x| x.green).sum();
+		let available = self.cycle.map(
+			|x|
+			x - transitions.iter().sum::<f32>()
+		);
+		self.overrun = available.is_some_and(|x| x <= 0.0);
+		let scale = match available {
+			Some(x) if x > 0.0 && green_sum > 0.0 => x / green_sum,
+			_ => 1.0
+		};
+		self.timings = self.stages.iter().zip(transitions).map(
+			|(stage, transition)|
+			(stage.green * scale, transition)
+		).collect();
+		self.segments = (0..self.groups.len()).map(
+			|x|
+			self.build_segments(x)
+		).collect();
+	}
+
+	// Green and transition of every stage, greens fitted to the cycle.
+	pub fn timings(&self) -> &[(f32, f32)] {
+		&self.timings
+	}
+
+	pub fn cycle_length(&self) -> f32 {
+		self.timings().iter().map(|x| x.0 + x.1).sum()
+	}
+
+	// Seconds into the cycle the stage's green starts.
+	pub fn stage_start(&self, stage: usize) -> f32 {
+		self.timings().iter().take(stage).map(|x| x.0 + x.1).sum()
+	}
+
+	// States of the group over one cycle with their durations, neighbours
+	// with the same state merged.
+	fn build_segments(&self, group: usize) -> Vec<(LightState, f32)> {
+		let mut result: Vec<(LightState, f32)> = Vec::new();
+		let mut push = |state: LightState, duration: f32| {
+			if duration <= 0.0 {
+				return;
+			}
+			match result.last_mut() {
+				Some(last) if last.0 == state => { last.1 += duration; },
+				_ => { result.push((state, duration)); }
+			}
+		};
+		for (i, (green, transition)) in self.timings.iter().copied().enumerate() {
+			let current = self.stages[i].groups.contains(&group);
+			let next = self.stages[(i + 1) % self.stages.len()].groups.contains(&group);
+			push(match current { true => LightState::Green, false => LightState::Red }, green);
+			match (current, next) {
+				(true, true) => push(LightState::Green, transition),
+				(true, false) => {
+					let yellow = self.groups[group].yellow.min(transition);
+					push(LightState::Yellow, yellow);
+					push(LightState::Red, transition - yellow);
+				},
+				(false, true) => {
+					let (ending, _) = self.changes(i);
+					let wait = ending.iter().filter(
+						|x|
+						self.conflicts[**x][group]
+					).fold(0.0, |a: f32, x| a.max(self.intergreen[*x][group])).min(transition);
+					push(LightState::Red, wait);
+					push(LightState::Green, transition - wait);
+				},
+				(false, false) => push(LightState::Red, transition)
+			}
+		}
+		result
+	}
+
+	// State of the group at time and the seconds until it changes.
+	pub fn state(&self, group: usize, time: f64) -> (LightState, f32) {
+		let segments = &self.segments[group];
+		let cycle: f32 = segments.iter().map(|x| x.1).sum();
+		if segments.len() <= 1 || cycle <= 0.0 {
+			return (segments.first().map_or(LightState::Green, |x| x.0), f32::INFINITY);
+		}
+		let mut t = (time + self.offset as f64).rem_euclid(cycle as f64) as f32;
+		for (i, (state, duration)) in segments.iter().enumerate() {
+			if t >= *duration && i + 1 < segments.len() {
+				t -= duration;
+				continue;
+			}
+			// The last segment runs on into the first one of the next cycle.
+			let mut remaining = duration - t;
+			if i + 1 == segments.len() && segments[0].0 == *state {
+				remaining += segments[0].1;
+			}
+			return (*state, remaining.max(0.0));
+		}
+		(LightState::Red, f32::INFINITY)
+	}
+}
+
+// Light of a lane showing the state of a group of a controller.
+#[derive(Debug, Clone)]
+pub struct GroupLight {
+	pub signal_identity: SignalIdentity,
+	pub controller: Arc<RwLock<SignalController>>,
+	pub group: usize,
+}
+
+#[async_trait]
+impl Signal for GroupLight {
+	fn identity(&self) -> &SignalIdentity {
+		&self.signal_identity
+	}
+
+	fn identity_mut(&mut self) -> &mut SignalIdentity {
+		&mut self.signal_identity
+	}
+
+	fn activate(&mut self,
+		_allocation: &NetworkAllocation,
+		_vehicle: &Vehicle
+	) {}
+
+	fn instruct(&mut self,
+		allocation: &NetworkAllocation,
+		vehicle: &Vehicle
+	) -> InstructResult {
+		let (state, remaining) = self.controller.read().unwrap().state(self.group, allocation.time());
+		light_instruct(allocation, vehicle, &self.signal_identity, state, remaining)
+	}
+}
+
+// Controllers along an arterial on a common cycle. Their offsets start the
+// arterial's green as a platoon driving at speed arrives, a green wave.
+#[derive(Debug)]
+pub struct Corridor {
+	pub cycle: f32,
+	pub speed: f32,
+	// Controller, its distance along the arterial from the first one and the
+	// stage serving the arterial.
+	pub intersections: Vec<(Arc<RwLock<SignalController>>, f32, usize)>,
+}
+
+impl Corridor {
+	pub fn new(cycle: f32, speed: f32) -> Self {
+		Self {
+			cycle,
+			speed,
+			intersections: Vec::new()
+		}
+	}
+
+	pub fn add(
+		&mut self,
+		controller: Arc<RwLock<SignalController>>,
+		distance: f32,
+		stage: usize
+	) {
+		self.intersections.push((controller, distance, stage));
+	}
+
+	// Sets the common cycle and the offsets of every controller. False when
+	// a controller does not fit the cycle, the wave breaks there.
+	pub fn coordinate(&self) -> bool {
+		let mut fits = true;
+		for (controller, distance, stage) in self.intersections.iter() {
+			let mut wa_controller = controller.write().unwrap();
+			wa_controller.set_cycle(Some(self.cycle));
+			fits &= wa_controller.fits_cycle();
+			let travel = match self.speed > 0.0 {
+				true => distance / self.speed,
+				false => 0.0
+			};
+			let start = wa_controller.stage_start(*stage);
+			wa_controller.offset = (start - travel).rem_euclid(self.cycle);
+		}
+		fits
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, RwLock};
+
+	use crate::network::signal::LightState;
+
+	use super::{SignalController, Corridor};
+
+	// Groups 0 and 1 conflict and are served one after the other, 20 and 10
+	// seconds of green.
+	fn crossing() -> SignalController {
+		let mut controller = SignalController::default();
+		controller.add_group(3.0);
+		controller.add_group(3.0);
+		assert!(controller.set_conflict(0, 1, 5.0, 4.0));
+		controller.add_stage(vec![0], 20.0).unwrap();
+		controller.add_stage(vec![1], 10.0).unwrap();
+		controller
+	}
+
+	#[test]
+	fn conflicting_stages_are_refused() {
+		let mut controller = crossing();
+		assert!(controller.add_stage(vec![0, 1], 10.0).is_none());
+		controller.add_group(3.0);
+		assert!(controller.add_stage(vec![0, 2], 10.0).is_some());
+		// The stage above would turn conflicting.
+		assert!(!controller.set_conflict(0, 2, 2.0, 2.0));
+		assert!(!controller.conflicts(0, 2));
+	}
+
+	#[test]
+	fn unknown_groups_are_refused() {
+		let mut controller = crossing();
+		assert!(!controller.set_conflict(0, 0, 2.0, 2.0));
+		assert!(!controller.set_conflict(0, 2, 2.0, 2.0));
+		assert!(!controller.conflicts(0, 2));
+		assert!(controller.add_stage(vec![2], 10.0).is_none());
+		assert!(controller.add_stage(vec![0, 5], 10.0).is_none());
+		assert_eq!(controller.stages().len(), 2);
+	}
+
+	#[test]
+	fn groups_wait_for_yellow_and_intergreen() {
+		let controller = crossing();
+		assert_eq!(controller.timings(), &[(20.0, 5.0), (10.0, 4.0)]);
+		assert_eq!(controller.cycle_length(), 39.0);
+		assert_eq!(controller.state(0, 0.0), (LightState::Green, 20.0));
+		assert_eq!(controller.state(0, 21.0), (LightState::Yellow, 2.0));
+		assert_eq!(controller.state(0, 24.0), (LightState::Red, 15.0));
+		assert_eq!(controller.state(1, 25.0), (LightState::Green, 10.0));
+		// Red runs on into the next cycle.
+		assert_eq!(controller.state(1, 38.5), (LightState::Red, 25.5));
+	}
+
+	#[test]
+	fn greens_are_fitted_to_the_cycle() {
+		let mut controller = crossing();
+		controller.set_cycle(Some(69.0));
+		assert!(controller.fits_cycle());
+		assert_eq!(controller.timings(), &[(40.0, 5.0), (20.0, 4.0)]);
+		assert_eq!(controller.state(0, 10.0), (LightState::Green, 30.0));
+		controller.set_cycle(Some(8.0));
+		assert!(!controller.fits_cycle());
+		assert_eq!(controller.cycle_length(), 39.0);
+	}
+
+	#[test]
+	fn corridor_starts_greens_with_the_platoon() {
+		let first = Arc::new(RwLock::new(crossing()));
+		let second = Arc::new(RwLock::new(crossing()));
+		let mut corridor = Corridor::new(69.0, 10.0);
+		corridor.add(first.clone(), 0.0, 0);
+		corridor.add(second.clone(), 200.0, 0);
+		assert!(corridor.coordinate());
+		assert_eq!(second.read().unwrap().offset, 49.0);
+		// The platoon leaving the first at 0 arrives at 20.
+		assert_eq!(second.read().unwrap().state(0, 20.0), (LightState::Green, 40.0));
+		corridor.cycle = 8.0;
+		assert!(!corridor.coordinate());
+	}
+}
@@ -0,0 +1,240 @@
+use std::{collections::{BTreeMap, VecDeque}, sync::Arc};
+
+use rand::Rng;
+
+use crate::network_allocation;
+
+use super::{Network, NetworkAllocation, lane::LaneIdentity, vehicle::{Vehicle, SpawnParams}, vehicle_type::VehicleKind, DAY_SECONDS};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Headway {
+	// Vehicles are spread evenly.
+	#[default]
+	Uniform,
+	// Exponentially distributed headways.
+	Poisson,
+}
+
+#[derive(Debug, Clone)]
+pub struct DemandProfile {
+	// Multipliers of the hourly flows. The day from midnight is split into
+	// one equal period per factor and the flows of a period are multiplied
+	// by its factor, 24 factors give hourly steps.
+	pub factors: Vec<f32>,
+}
+
+impl Default for DemandProfile {
+	fn default() -> Self {
+		Self {
+			factors: vec![1.0]
+		}
+	}
+}
+
+impl DemandProfile {
+	pub fn factor(&self, time: f64) -> f32 {
+		if self.factors.is_empty() {
+			return 1.0;
+		}
+		let time_of_day = time.rem_euclid(DAY_SECONDS);
+		let idx = (time_of_day / DAY_SECONDS * self.factors.len() as f64) as usize;
+		self.factors[idx.min(self.factors.len() - 1)]
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OdPair {
+	pub origin: LaneIdentity,
+	pub destination: LaneIdentity,
+	// Vehicles per hour before the profile factor.
+	pub flow: f32,
+	pub vehicle_type: VehicleKind,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct OdState {
+	// Expected vehicles accumulated since the last spawn.
+	progress: f64,
+	// Expected vehicles between the last and the next spawn.
+	threshold: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Demand {
+	pub pairs: Vec<OdPair>,
+	pub profile: DemandProfile,
+	pub headway: Headway,
+	// Free distance needed at the start of the origin lane to insert.
+	pub insertion_gap: f32,
+	// Pair indices of vehicles waiting for space on their origin lane. In
+	// lane order, so seeded runs insert in the same order.
+	pub queues: BTreeMap<u32, VecDeque<usize>>,
+	states: Vec<OdState>,
+}
+
+impl Demand {
+	pub fn new(
+		pairs: Vec<OdPair>,
+		profile: DemandProfile,
+		headway: Headway
+	) -> Self {
+		Self {
+			pairs,
+			profile,
+			headway,
+			insertion_gap: 10.0,
+			..Default::default()
+		}
+	}
+
+	// Call once per simulation step. Vehicles that are due are queued on
+	// their origin lane and inserted once the lane has space.
+	pub fn tick(
+		&mut self,
+		network: &Arc<Network>,
+		delta_time: f32
+	) {
+		let network_c = network.clone();
+		let allocation = network_allocation!(network_c);
+		let factor = self.profile.factor(allocation.time()) as f64;
+
+		// ARRIVALS
+
+		if self.states.len() != self.pairs.len() {
+			self.states.resize(self.pairs.len(), OdState::default());
+		}
+		for (i, pair) in self.pairs.iter().enumerate() {
+			let state = &mut self.states[i];
+			if state.threshold <= 0.0 {
+				state.threshold = self.headway.draw(allocation);
+			}
+			state.progress += pair.flow as f64 / 3600.0 * factor * delta_time as f64;
+			while state.progress >= state.threshold {
+				state.progress -= state.threshold;
+				state.threshold = self.headway.draw(allocation);
+				self.queues.entry(pair.origin.lane).or_default().push_back(i);
+			}
+		}
+
+		// INSERTION
+
+		for queue in self.queues.values_mut() {
+			let pair = match queue.front() {
+				Some(x) => self.pairs[*x],
+				None => { continue; }
+			};
+			let params = SpawnParams {
+				vehicle_kind: pair.vehicle_type,
+				clearance: self.insertion_gap,
+				..Default::default()
+			};
+			if Vehicle::spawn(network, pair.origin, pair.destination, params).is_some() {
+				queue.pop_front();
+			}
+		}
+	}
+
+	// Ticks every demand in NetworkAllocation::demands.
+	pub fn tick_all(
+		network: &Arc<Network>,
+		delta_time: f32
+	) {
+		let network_c = network.clone();
+		let allocation = network_allocation!(network_c);
+		let mut wa_demands = allocation.demands.write().unwrap();
+		for demand in wa_demands.iter_mut() {
+			demand.tick(network, delta_time);
+		}
+	}
+
+	pub fn queued(&self) -> usize {
+		self.queues.values().map(|x| x.len()).sum()
+	}
+}
+
+impl Headway {
+	fn draw(&self, allocation: &NetworkAllocation) -> f64 {
+		match self {
+			Headway::Uniform => 1.0,
+			Headway::Poisson => {
+				let u: f64 = allocation.rng.write().unwrap().0.gen::<f64>();
+				-(1.0 - u).ln()
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::network::{testing, vehicle::{Vehicle, SpawnParams}, Network, DAY_SECONDS};
+
+	use super::{Demand, DemandProfile, Headway, OdPair};
+
+	#[test]
+	fn profile_steps_over_the_day() {
+		let profile = DemandProfile {
+			factors: vec![1.0, 2.0, 3.0, 4.0]
+		};
+		assert_eq!(profile.factor(0.0), 1.0);
+		assert_eq!(profile.factor(5.9 * 3_600.0), 1.0);
+		assert_eq!(profile.factor(6.0 * 3_600.0), 2.0);
+		assert_eq!(profile.factor(DAY_SECONDS + 23.0 * 3_600.0), 4.0);
+	}
+
+	// Origin lane of every spawned vehicle in id order.
+	fn run(seed: u64) -> (Arc<Network>, Vec<u32>) {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		allocation.seed(seed);
+		let pairs = vec![
+			OdPair { origin: lanes[1], destination: lanes[8], flow: 1_800.0, ..Default::default() },
+			OdPair { origin: lanes[0], destination: lanes[6], flow: 1_800.0, ..Default::default() }
+		];
+		allocation.demands.write().unwrap().push(Demand::new(pairs, DemandProfile::default(), Headway::Poisson));
+		let mut origins: Vec<(u32, u32)> = Vec::new();
+		for _ in 0..400 {
+			Network::tick(&network, 0.05);
+			for lane in [lanes[0].lane, lanes[1].lane] {
+				for vehicle in allocation.lane(lane).read().unwrap().vehicles.iter() {
+					if !origins.iter().any(|x| x.0 == vehicle.identity.sub) {
+						origins.push((vehicle.identity.sub, lane));
+					}
+				}
+			}
+		}
+		origins.sort();
+		(network, origins.iter().map(
+			|x|
+			x.1
+		).collect())
+	}
+
+	#[test]
+	fn seeded_runs_repeat() {
+		let (_, first) = run(7);
+		let (_, second) = run(7);
+		assert!(first.len() >= 4);
+		assert_eq!(first, second);
+	}
+
+	#[test]
+	fn insertion_keeps_the_gap_to_upstream_lanes() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		// Stands 5 short of the end of lane 1, which leads onto lane 3.
+		let id = Vehicle::spawn(&network, lanes[0], lanes[6], SpawnParams {
+			distance: 145.0,
+			..Default::default()
+		}).unwrap();
+		testing::with_vehicle(&network, id.sub, |x| x.hold(allocation, 100.0));
+		let pairs = vec![OdPair { origin: lanes[2], destination: lanes[6], flow: 36_000.0, ..Default::default() }];
+		allocation.demands.write().unwrap().push(Demand::new(pairs, DemandProfile::default(), Headway::Uniform));
+		for _ in 0..40 {
+			Network::tick(&network, 0.05);
+		}
+		assert!(allocation.lane(lanes[2].lane).read().unwrap().vehicles.is_empty());
+		assert!(allocation.demands.read().unwrap()[0].queued() > 0);
+	}
+}
@@ -0,0 +1,32 @@
+use super::{vehicle::VehicleIdentity, lane::LaneIdentity};
+
+// Emitted by the simulation into NetworkAllocation::events. Drain them with
+// NetworkAllocation::drain_events every tick, the queue is unbounded.
+#[derive(Debug, Clone, Copy)]
+pub enum NetworkEvent {
+	WaypointReached {
+		vehicle: VehicleIdentity,
+		waypoint: usize,
+		identity: LaneIdentity,
+	},
+	// The vehicle reached a sink or the end of its route and was removed.
+	VehicleExited {
+		vehicle: VehicleIdentity,
+	},
+	// Two vehicles started to overlap. first is the one in front.
+	Collision {
+		first: VehicleIdentity,
+		second: VehicleIdentity,
+		lane: u32,
+		overlap: f32,
+	},
+	IncidentStarted {
+		vehicles: [VehicleIdentity; 2],
+		lane: u32,
+		duration: f32,
+	},
+	IncidentCleared {
+		vehicles: [VehicleIdentity; 2],
+		lane: u32,
+	},
+}
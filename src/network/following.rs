@@ -0,0 +1,358 @@
+use core::fmt;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use super::{vehicle::{Vehicle, VehicleData}, signal::InstructSlow, NetworkAllocation};
+
+#[derive(Debug, Clone, Copy)]
+pub struct FollowingInput {
+	// Nearest forward vehicle, its distance is relative to the vehicle.
+	pub leader: Option<VehicleData>,
+	pub lane_speed: f32,
+	// Slowest instruction of the active signals.
+	pub signal: InstructSlow,
+	pub delta_time: f32,
+}
+
+impl FollowingInput {
+	pub fn desired_speed(&self) -> f32 {
+		self.lane_speed.min(self.signal.target_speed)
+	}
+}
+
+// Longitudinal behaviour of a vehicle. Returns the acceleration the vehicle
+// drives with this tick. Takes the vehicle mutably so models can keep state
+// in it, as the pedal model does.
+pub trait CarFollowingModel: Send + Sync {
+	fn acceleration(
+		&self,
+		allocation: &NetworkAllocation,
+		vehicle: &mut Vehicle,
+		input: &FollowingInput
+	) -> f32;
+
+	fn name(&self) -> &str {
+		"custom"
+	}
+
+	// Whether the model delays its reaction by the driver's reaction time
+	// itself. The vehicle then perceives without delay, so it does not react
+	// twice as late.
+	fn includes_reaction(&self) -> bool {
+		false
+	}
+}
+
+impl fmt::Debug for dyn CarFollowingModel {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+#[derive(Debug, Default, Clone)]
+pub enum FollowingModel {
+	#[default]
+	Pedal,
+	Idm(Idm),
+	Gipps(Gipps),
+	Krauss(Krauss),
+	Custom(Arc<dyn CarFollowingModel>),
+}
+
+impl FollowingModel {
+	pub fn model(&self) -> &dyn CarFollowingModel {
+		match self {
+			FollowingModel::Pedal => &PedalModel,
+			FollowingModel::Idm(x) => x,
+			FollowingModel::Gipps(x) => x,
+			FollowingModel::Krauss(x) => x,
+			FollowingModel::Custom(x) => x.as_ref(),
+		}
+	}
+}
+
+// Throttle and brake state machine of VTarget and VStage.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PedalModel;
+
+impl CarFollowingModel for PedalModel {
+	fn acceleration(
+		&self,
+		_allocation: &NetworkAllocation,
+		vehicle: &mut Vehicle,
+		input: &FollowingInput
+	) -> f32 {
+		vehicle.pedal_acceleration(input)
+	}
+
+	fn name(&self) -> &str {
+		"pedal"
+	}
+}
+
+// Intelligent driver model.
+#[derive(Debug, Clone, Copy)]
+pub struct Idm {
+	pub max_accel: f32,
+	pub comfortable_decel: f32,
+	pub min_gap: f32,
+	// Desired seconds to the leader. Vehicles driving with the model use
+	// their driver's time headway instead.
+	pub time_headway: f32,
+	pub exponent: f32,
+}
+
+impl Default for Idm {
+	fn default() -> Self {
+		Self {
+			max_accel: 20.0,
+			comfortable_decel: 30.0,
+			min_gap: 10.0,
+			time_headway: 1.0,
+			exponent: 4.0
+		}
+	}
+}
+
+impl Idm {
+	pub fn acceleration_to(
+		&self,
+		speed: f32,
+		desired_speed: f32,
+		leader: Option<&VehicleData>
+	) -> f32 {
+		let free = match desired_speed > 0.0 {
+			true => 1.0 - (speed / desired_speed).powf(self.exponent),
+			false => -1.0
+		};
+		let interaction = match leader {
+			Some(leader) => {
+				let approach = speed - leader.speed;
+				let desired_gap = self.min_gap + (
+					speed * self.time_headway +
+					speed * approach / (2.0 * (self.max_accel * self.comfortable_decel).sqrt())
+				).max(0.0);
+				(desired_gap / leader.distance.max(0.1)).powi(2)
+			},
+			None => 0.0
+		};
+		self.max_accel * (free - interaction)
+	}
+}
+
+impl CarFollowingModel for Idm {
+	fn acceleration(
+		&self,
+		_allocation: &NetworkAllocation,
+		vehicle: &mut Vehicle,
+		input: &FollowingInput
+	) -> f32 {
+		let idm = Idm {
+			time_headway: vehicle.driver_personality.time_headway,
+			..*self
+		};
+		idm.acceleration_to(vehicle.data.speed, input.desired_speed(), input.leader.as_ref())
+	}
+
+	fn name(&self) -> &str {
+		"idm"
+	}
+}
+
+// Gipps model, the lower of a free flow and a safe braking speed.
+#[derive(Debug, Clone, Copy)]
+pub struct Gipps {
+	pub max_accel: f32,
+	pub max_decel: f32,
+	// Deceleration the driver assumes of the leader.
+	pub leader_decel: f32,
+	pub min_gap: f32,
+	// Vehicles driving with the model use their driver's reaction time.
+	pub reaction_time: f32,
+}
+
+impl Default for Gipps {
+	fn default() -> Self {
+		Self {
+			max_accel: 20.0,
+			max_decel: 40.0,
+			leader_decel: 40.0,
+			min_gap: 10.0,
+			reaction_time: 0.7
+		}
+	}
+}
+
+impl CarFollowingModel for Gipps {
+	fn acceleration(
+		&self,
+		_allocation: &NetworkAllocation,
+		vehicle: &mut Vehicle,
+		input: &FollowingInput
+	) -> f32 {
+		let speed = vehicle.data.speed;
+		let desired_speed = input.desired_speed().max(0.1);
+		let tau = vehicle.driver_personality.reaction_time.max(0.1);
+		let ratio = (speed / desired_speed).min(1.0);
+		let mut next_speed = speed + 2.5 * self.max_accel * tau * (1.0 - ratio) * (0.025 + ratio).sqrt();
+		if speed > desired_speed {
+			next_speed = desired_speed.max(speed - self.max_decel * tau);
+		}
+		if let Some(leader) = input.leader {
+			let b = self.max_decel;
+			let root = b * b * tau * tau + b * (
+				2.0 * (leader.distance - self.min_gap) - speed * tau + leader.speed * leader.speed / self.leader_decel
+			);
+			let safe_speed = -b * tau + root.max(0.0).sqrt();
+			next_speed = next_speed.min(safe_speed);
+		}
+		(next_speed.max(0.0) - speed) / tau
+	}
+
+	fn name(&self) -> &str {
+		"gipps"
+	}
+
+	fn includes_reaction(&self) -> bool {
+		true
+	}
+}
+
+// Krauss model, a safe speed with random dawdling drawn from the network rng.
+#[derive(Debug, Clone, Copy)]
+pub struct Krauss {
+	pub max_accel: f32,
+	pub max_decel: f32,
+	pub min_gap: f32,
+	// Vehicles driving with the model use their driver's reaction time.
+	pub reaction_time: f32,
+	// Imperfection between 0 and 1.
+	pub sigma: f32,
+}
+
+impl Default for Krauss {
+	fn default() -> Self {
+		Self {
+			max_accel: 20.0,
+			max_decel: 40.0,
+			min_gap: 10.0,
+			reaction_time: 1.0,
+			sigma: 0.5
+		}
+	}
+}
+
+impl CarFollowingModel for Krauss {
+	fn acceleration(
+		&self,
+		allocation: &NetworkAllocation,
+		vehicle: &mut Vehicle,
+		input: &FollowingInput
+	) -> f32 {
+		let speed = vehicle.data.speed;
+		let reaction_time = vehicle.driver_personality.reaction_time;
+		let delta_time = input.delta_time.max(f32::EPSILON);
+		let mut next_speed = input.desired_speed().min(speed + self.max_accel * delta_time);
+		if let Some(leader) = input.leader {
+			let gap = leader.distance - self.min_gap;
+			let safe_speed = leader.speed + (gap - leader.speed * reaction_time) /
+				((speed + leader.speed) / (2.0 * self.max_decel) + reaction_time);
+			next_speed = next_speed.min(safe_speed);
+		}
+		let dawdle = allocation.rng.write().unwrap().0.gen::<f32>();
+		next_speed -= self.sigma * self.max_accel * delta_time * dawdle;
+		(next_speed.max(0.0) - speed) / delta_time
+	}
+
+	fn name(&self) -> &str {
+		"krauss"
+	}
+
+	fn includes_reaction(&self) -> bool {
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::network::{vehicle::{Vehicle, VehicleData}, signal::InstructSlow, NetworkAllocation};
+
+	use super::{CarFollowingModel, FollowingInput, FollowingModel, Gipps, Idm, Krauss};
+
+	fn input(leader: Option<VehicleData>) -> FollowingInput {
+		FollowingInput {
+			leader,
+			lane_speed: 100.0,
+			signal: InstructSlow {
+				target_speed: f32::INFINITY,
+				..Default::default()
+			},
+			delta_time: 0.1
+		}
+	}
+
+	fn standing(distance: f32) -> Option<VehicleData> {
+		Some(VehicleData {
+			distance,
+			speed: 0.0,
+			..Default::default()
+		})
+	}
+
+	fn accelerations(model: &dyn CarFollowingModel, speed: f32) -> (f32, f32, f32) {
+		let allocation = NetworkAllocation::default();
+		let mut vehicle = Vehicle::default();
+		vehicle.driver_personality.time_headway = 1.0;
+		vehicle.data.speed = speed;
+		(
+			model.acceleration(&allocation, &mut vehicle, &input(None)),
+			model.acceleration(&allocation, &mut vehicle, &input(standing(200.0))),
+			model.acceleration(&allocation, &mut vehicle, &input(standing(15.0)))
+		)
+	}
+
+	#[test]
+	fn idm_accelerates_free_and_brakes_behind_leader() {
+		let idm = Idm::default();
+		assert_eq!(idm.acceleration_to(0.0, 100.0, None), idm.max_accel);
+		assert!(idm.acceleration_to(100.0, 100.0, None).abs() < 1e-4);
+		let (free, far, near) = accelerations(&idm, 50.0);
+		assert!(free > far && far > near);
+		assert!(near < -idm.comfortable_decel);
+	}
+
+	#[test]
+	fn gipps_keeps_a_safe_speed() {
+		let gipps = Gipps::default();
+		let (free, far, near) = accelerations(&gipps, 50.0);
+		assert!(free > 0.0);
+		assert!(far <= free);
+		assert!(near < 0.0);
+		// Never faster than the desired speed.
+		let (free, _, _) = accelerations(&gipps, 120.0);
+		assert!(free < 0.0);
+	}
+
+	#[test]
+	fn krauss_without_dawdling() {
+		let krauss = Krauss {
+			sigma: 0.0,
+			..Default::default()
+		};
+		let (free, far, near) = accelerations(&krauss, 50.0);
+		assert_eq!(free, krauss.max_accel);
+		assert!(far <= free);
+		assert!(near < 0.0);
+	}
+
+	#[test]
+	fn custom_models_show_their_name() {
+		let model = FollowingModel::Custom(Arc::new(Krauss::default()));
+		assert_eq!(format!("{:?}", model), "Custom(krauss)");
+		assert!(FollowingModel::Gipps(Gipps::default()).model().includes_reaction());
+		assert!(!FollowingModel::Idm(Idm::default()).model().includes_reaction());
+	}
+}
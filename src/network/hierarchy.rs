@@ -0,0 +1,386 @@
+use std::{collections::{HashMap, BinaryHeap, HashSet}, cmp::Ordering};
+
+use super::NetworkAllocation;
+
+// Settled node limit of a witness search. Lower limits contract faster, but
+// may add shortcuts that are not needed.
+const WITNESS_SETTLE_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+	node: usize,
+	cost: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct QueueEntry {
+	pub(crate) cost: f64,
+	pub(crate) node: usize,
+}
+
+impl Eq for QueueEntry {}
+
+impl Ord for QueueEntry {
+	fn cmp(&self, other: &Self) -> Ordering {
+		// Reversed for a min heap.
+		other.cost.partial_cmp(&self.cost).expect("invalid distance")
+			.then_with(|| other.node.cmp(&self.node))
+	}
+}
+
+impl PartialOrd for QueueEntry {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+// Contraction hierarchy over the band graph. A band connects to every band
+// in its destination clip's fw_bands, with the same costs as the A* of
+// Navigation::renavigate. Built by NetworkAllocation::build_hierarchy and
+// dropped whenever a clip, band or lane is added.
+#[derive(Debug, Default)]
+pub struct ContractionHierarchy {
+	bands: Vec<u32>,
+	index: HashMap<u32, usize>,
+	// Edges to higher ranked nodes, searched forward from the source.
+	up: Vec<Vec<Edge>>,
+	// Edges from higher ranked nodes, searched backward from the target.
+	down: Vec<Vec<Edge>>,
+	// Contracted node each shortcut bypasses.
+	middle: HashMap<(usize, usize), usize>,
+}
+
+impl ContractionHierarchy {
+	pub fn build(
+		allocation: &NetworkAllocation
+	) -> Self {
+		let mut hierarchy = Self::default();
+
+		// NODES
+
+		let ra_bands = allocation.bands.read().unwrap();
+		let mut band_ids: Vec<u32> = ra_bands.keys().copied().collect();
+		band_ids.sort();
+		for band_id in band_ids.iter() {
+			hierarchy.index.insert(*band_id, hierarchy.bands.len());
+			hierarchy.bands.push(*band_id);
+		}
+		let count = hierarchy.bands.len();
+
+		// EDGES
+
+		let mut out_edges: Vec<HashMap<usize, f64>> = vec![HashMap::new(); count];
+		let mut in_edges: Vec<HashMap<usize, f64>> = vec![HashMap::new(); count];
+		for band_id in band_ids.iter() {
+			let from = hierarchy.index[band_id];
+			let dst_clip = ra_bands[band_id].read().unwrap().dst_clip;
+			let c_clip = allocation.clip(dst_clip);
+			let ra_clip = c_clip.read().unwrap();
+			for fw_band in ra_clip.fw_bands.iter() {
+				let band_lane_idx = ra_bands[fw_band].read().unwrap().src_min;
+				let length = allocation.lane(
+					ra_clip.lanes_fixed[band_lane_idx as usize].fw[0]
+				).read().unwrap().length as f64;
+				let to = hierarchy.index[fw_band];
+				if from == to {
+					continue;
+				}
+				out_edges[from].insert(to, length);
+				in_edges[to].insert(from, length);
+			}
+		}
+		drop(ra_bands);
+
+		// CONTRACT
+
+		hierarchy.up = vec![Vec::new(); count];
+		hierarchy.down = vec![Vec::new(); count];
+		let mut contracted: Vec<bool> = vec![false; count];
+		let mut contracted_neighbours: Vec<i32> = vec![0; count];
+		let mut queue: BinaryHeap<QueueEntry> = BinaryHeap::new();
+		for node in 0..count {
+			let shortcuts = Self::shortcuts(&out_edges, &in_edges, &contracted, node);
+			queue.push(QueueEntry {
+				cost: Self::priority(&out_edges, &in_edges, shortcuts.len(), 0, node),
+				node
+			});
+		}
+		while let Some(entry) = queue.pop() {
+			let node = entry.node;
+			if contracted[node] {
+				continue;
+			}
+
+			// LAZY UPDATE
+
+			let shortcuts = Self::shortcuts(&out_edges, &in_edges, &contracted, node);
+			let priority = Self::priority(
+				&out_edges,
+				&in_edges,
+				shortcuts.len(),
+				contracted_neighbours[node],
+				node
+			);
+			if priority > queue.peek().map_or(f64::INFINITY, |x| x.cost) {
+				queue.push(QueueEntry { cost: priority, node });
+				continue;
+			}
+
+			// REMOVE NODE
+
+			contracted[node] = true;
+			for (to, cost) in out_edges[node].drain() {
+				in_edges[to].remove(&node);
+				contracted_neighbours[to] += 1;
+				hierarchy.up[node].push(Edge { node: to, cost });
+			}
+			for (from, cost) in in_edges[node].drain() {
+				out_edges[from].remove(&node);
+				contracted_neighbours[from] += 1;
+				hierarchy.down[node].push(Edge { node: from, cost });
+			}
+			for (from, to, cost) in shortcuts {
+				let existing = out_edges[from].get(&to).copied().unwrap_or(f64::INFINITY);
+				if cost < existing {
+					out_edges[from].insert(to, cost);
+					in_edges[to].insert(from, cost);
+					hierarchy.middle.insert((from, to), node);
+				}
+			}
+		}
+
+		hierarchy
+	}
+
+	fn priority(
+		out_edges: &[HashMap<usize, f64>],
+		in_edges: &[HashMap<usize, f64>],
+		shortcut_count: usize,
+		contracted_neighbours: i32,
+		node: usize
+	) -> f64 {
+		// Edge difference plus contracted neighbours, which spreads the
+		// contraction uniformly over the graph.
+		let removed = (out_edges[node].len() + in_edges[node].len()) as i32;
+		(shortcut_count as i32 - removed + contracted_neighbours) as f64
+	}
+
+	fn shortcuts(
+		out_edges: &[HashMap<usize, f64>],
+		in_edges: &[HashMap<usize, f64>],
+		contracted: &[bool],
+		node: usize
+	) -> Vec<(usize, usize, f64)> {
+		let mut result: Vec<(usize, usize, f64)> = Vec::new();
+		for (from, in_cost) in in_edges[node].iter() {
+			let max_cost = out_edges[node].values().fold(0.0, |a: f64, &b| a.max(b)) + in_cost;
+			let witness = Self::witness_search(out_edges, contracted, *from, node, max_cost);
+			for (to, out_cost) in out_edges[node].iter() {
+				if to == from {
+					continue;
+				}
+				let cost = in_cost + out_cost;
+				let witness_cost = witness.get(to).copied().unwrap_or(f64::INFINITY);
+				if witness_cost > cost {
+					result.push((*from, *to, cost));
+				}
+			}
+		}
+		result
+	}
+
+	fn witness_search(
+		out_edges: &[HashMap<usize, f64>],
+		contracted: &[bool],
+		source: usize,
+		ignore: usize,
+		max_cost: f64
+	) -> HashMap<usize, f64> {
+		let mut distance: HashMap<usize, f64> = HashMap::new();
+		let mut settled: HashSet<usize> = HashSet::new();
+		let mut queue: BinaryHeap<QueueEntry> = BinaryHeap::new();
+		distance.insert(source, 0.0);
+		queue.push(QueueEntry { cost: 0.0, node: source });
+		while let Some(entry) = queue.pop() {
+			if entry.cost > max_cost || settled.len() >= WITNESS_SETTLE_LIMIT {
+				break;
+			}
+			if !settled.insert(entry.node) {
+				continue;
+			}
+			for (to, cost) in out_edges[entry.node].iter() {
+				if *to == ignore || contracted[*to] {
+					continue;
+				}
+				let pos_cost = entry.cost + cost;
+				if pos_cost < distance.get(to).copied().unwrap_or(f64::INFINITY) {
+					distance.insert(*to, pos_cost);
+					queue.push(QueueEntry { cost: pos_cost, node: *to });
+				}
+			}
+		}
+		distance
+	}
+
+	// Shortest band path from src_band to dst_band, both included.
+	pub fn path(
+		&self,
+		src_band: u32,
+		dst_band: u32
+	) -> Option<(Vec<u32>, f64)> {
+		let source = *self.index.get(&src_band)?;
+		let target = *self.index.get(&dst_band)?;
+		if source == target {
+			return Some((vec![src_band], 0.0));
+		}
+
+		// BIDIRECTIONAL SEARCH
+
+		let mut fw_distance: HashMap<usize, f64> = HashMap::new();
+		let mut bw_distance: HashMap<usize, f64> = HashMap::new();
+		let mut fw_preceding: HashMap<usize, usize> = HashMap::new();
+		let mut bw_preceding: HashMap<usize, usize> = HashMap::new();
+		let mut fw_queue: BinaryHeap<QueueEntry> = BinaryHeap::new();
+		let mut bw_queue: BinaryHeap<QueueEntry> = BinaryHeap::new();
+		fw_distance.insert(source, 0.0);
+		bw_distance.insert(target, 0.0);
+		fw_queue.push(QueueEntry { cost: 0.0, node: source });
+		bw_queue.push(QueueEntry { cost: 0.0, node: target });
+		let mut best: f64 = f64::INFINITY;
+		let mut meeting: Option<usize> = None;
+		loop {
+			let fw_min = fw_queue.peek().map_or(f64::INFINITY, |x| x.cost);
+			let bw_min = bw_queue.peek().map_or(f64::INFINITY, |x| x.cost);
+			if fw_min >= best && bw_min >= best {
+				break;
+			}
+			let forward = fw_min <= bw_min;
+			let (queue, distance, other_distance, preceding, edges) = match forward {
+				true => (&mut fw_queue, &mut fw_distance, &bw_distance, &mut fw_preceding, &self.up),
+				false => (&mut bw_queue, &mut bw_distance, &fw_distance, &mut bw_preceding, &self.down)
+			};
+			let entry = queue.pop().unwrap();
+			if entry.cost > distance[&entry.node] {
+				continue;
+			}
+			if let Some(other) = other_distance.get(&entry.node) {
+				if entry.cost + other < best {
+					best = entry.cost + other;
+					meeting = Some(entry.node);
+				}
+			}
+			for edge in edges[entry.node].iter() {
+				let pos_cost = entry.cost + edge.cost;
+				if pos_cost < distance.get(&edge.node).copied().unwrap_or(f64::INFINITY) {
+					distance.insert(edge.node, pos_cost);
+					preceding.insert(edge.node, entry.node);
+					queue.push(QueueEntry { cost: pos_cost, node: edge.node });
+				}
+			}
+		}
+		let meeting = meeting?;
+
+		// RECONSTRUCT
+
+		let mut packed: Vec<usize> = vec![meeting];
+		let mut current = meeting;
+		while let Some(x) = fw_preceding.get(&current) {
+			packed.insert(0, *x);
+			current = *x;
+		}
+		current = meeting;
+		while let Some(x) = bw_preceding.get(&current) {
+			packed.push(*x);
+			current = *x;
+		}
+
+		// UNPACK SHORTCUTS
+
+		let mut path: Vec<u32> = vec![self.bands[packed[0]]];
+		for pair in packed.windows(2) {
+			self.unpack(pair[0], pair[1], &mut path);
+		}
+		Some((path, best))
+	}
+
+	fn unpack(
+		&self,
+		from: usize,
+		to: usize,
+		path: &mut Vec<u32>
+	) {
+		match self.middle.get(&(from, to)) {
+			Some(middle) => {
+				self.unpack(from, *middle, path);
+				self.unpack(*middle, to, path);
+			},
+			None => {
+				path.push(self.bands[to]);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use crate::network::{testing, NetworkAllocation};
+
+	use super::ContractionHierarchy;
+
+	// Band edges and their costs as the hierarchy builds them.
+	fn edges(allocation: &NetworkAllocation) -> HashMap<(u32, u32), f64> {
+		let mut result: HashMap<(u32, u32), f64> = HashMap::new();
+		for (id, band) in allocation.bands.read().unwrap().iter() {
+			let c_clip = allocation.clip(band.read().unwrap().dst_clip);
+			let ra_clip = c_clip.read().unwrap();
+			for fw_band in ra_clip.fw_bands.iter() {
+				let lane_idx = allocation.band(*fw_band).read().unwrap().src_min;
+				let length = allocation.lane(ra_clip.lanes_fixed[lane_idx as usize].fw[0]).read().unwrap().length;
+				result.insert((*id, *fw_band), length as f64);
+			}
+		}
+		result
+	}
+
+	#[test]
+	fn paths_match_plain_search() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let hierarchy = ContractionHierarchy::build(allocation);
+		let edges = edges(allocation);
+		let bands: Vec<u32> = (1..=10).collect();
+		for src in bands.iter() {
+			// Bellman-Ford from src.
+			let mut distance: HashMap<u32, f64> = HashMap::from([(*src, 0.0)]);
+			for _ in 0..bands.len() {
+				for ((a, b), cost) in edges.iter() {
+					if let Some(x) = distance.get(a).copied() {
+						if x + cost < distance.get(b).copied().unwrap_or(f64::INFINITY) {
+							distance.insert(*b, x + cost);
+						}
+					}
+				}
+			}
+			for dst in bands.iter() {
+				let result = hierarchy.path(*src, *dst);
+				let expected = match distance.get(dst) {
+					Some(x) => *x,
+					None => {
+						assert!(result.is_none(), "{} to {}", src, dst);
+						continue;
+					}
+				};
+				let (path, cost) = result.expect("path missing");
+				assert_eq!((path[0], path[path.len() - 1]), (*src, *dst));
+				assert!((cost - expected).abs() < 1e-3, "{} to {}", src, dst);
+				let walked: f64 = path.windows(2).map(
+					|x|
+					edges[&(x[0], x[1])]
+				).sum();
+				assert!((walked - expected).abs() < 1e-3, "{} to {}", src, dst);
+			}
+		}
+	}
+}
@@ -1,11 +1,11 @@
-use std::sync::{atomic::Ordering, Arc, RwLock};
+use std::{collections::HashSet, sync::{atomic::Ordering, Arc, RwLock}};
 
 use nalgebra::Vector2;
 
 
 use crate::{network::{navigation::Point, clip::Fixed, LANE_MAX_BRANCH}, network_allocation};
 
-use super::{Network, vehicle::{VehicleData}, signal::Signal};
+use super::{Network, NetworkAllocation, vehicle::{VehicleData}, signal::Signal};
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct LaneIdentity {
@@ -14,6 +14,18 @@ pub struct LaneIdentity {
 	pub clip: u32,
 }
 
+// Vehicle found upstream of a position.
+#[derive(Debug, Clone, Copy)]
+pub struct Upstream {
+	pub vehicle: VehicleData,
+	// Lane the vehicle is on.
+	pub lane: u32,
+	// From the vehicle's front to the position, along the lanes.
+	pub gap: f32,
+	// Vehicle speed minus the speed asked with, positive when closing in.
+	pub relative_speed: f32,
+}
+
 #[derive(Debug)]
 pub struct Lane {
 	pub identity: LaneIdentity,
@@ -28,8 +40,13 @@ pub struct Lane {
 	pub points: Vec<Point>,
 	pub length: f32,
 
+	// Ordered by distance, rear first. Change it through insert_vehicle,
+	// update_vehicle and remove_vehicle to keep the order.
 	pub vehicles: Vec<VehicleData>,
-	pub signals: Vec<Arc<dyn Signal>>
+	pub signals: Vec<Arc<dyn Signal>>,
+	// Vehicles reaching the end leave the network, even when their route
+	// goes on.
+	pub sink: bool,
 }
 
 impl Lane {
@@ -117,13 +134,16 @@ impl Lane {
 				bw_lanes: Vec::new(),
 				length: accumulated_distance,
 				vehicles: Vec::new(),
-				signals: Vec::new()
+				signals: Vec::new(),
+				sink: false
 			})
 		));
 		drop(wa_allocation_lanes);
 
 		// UPDATE CLIP -> LANE & LANE -> LANE
 
+		let bw_lanes: Vec<u32>;
+		let fw_lanes: Vec<u32>;
 		let c_clip_fw = allocation.clip(clip_fw);
 		let mut wa_clip_fw = c_clip_fw.write().unwrap();
 		let c_clip_bw = allocation.clip(clip_bw);
@@ -144,6 +164,7 @@ impl Lane {
 				lane_fixed.fw[lane_fixed.fw_count as usize] = id;
 				lane_fixed.fw_count += 1;
 			}
+			bw_lanes = lane_fixed.bw[..lane_fixed.bw_count as usize].to_vec();
 			for i in 0..lane_fixed.bw_count {
 				let c_lane_bw = allocation.lane(lane_fixed.bw[i as usize]);
 				let mut wa_lane_bw = c_lane_bw.write().unwrap();
@@ -183,6 +204,7 @@ impl Lane {
 				lane_fixed.bw[lane_fixed.bw_count as usize] = id;
 				lane_fixed.bw_count += 1;
 			}
+			fw_lanes = lane_fixed.fw[..lane_fixed.fw_count as usize].to_vec();
 			for i in 0..lane_fixed.fw_count {
 				let c_lane_fw = allocation.lane(lane_fixed.fw[i as usize]);
 				let mut wa_lane_fw = c_lane_fw.write().unwrap();
@@ -190,6 +212,8 @@ impl Lane {
 			}
 		}
 
+		Self::link(allocation, id, &bw_lanes, &fw_lanes);
+
 		// RESIZE BAND
 
 		let c_band = allocation.band(band);
@@ -216,9 +240,438 @@ impl Lane {
 		if !clip_bw_w.fw_bands.contains(&band) {
 			clip_bw_w.fw_bands.push(band);
 		}
+		allocation.invalidate_hierarchy();
 
 		// println!("...aquired lane id {}", id);
 
 		id
 	}
-}
\ No newline at end of file
+
+	// Lanes of the same band directly beside this lane at its source clip.
+	pub fn lateral_lanes(
+		&self,
+		allocation: &NetworkAllocation
+	) -> Vec<LaneIdentity> {
+		let c_clip = allocation.clip(self.identity.clip);
+		let ra_clip = c_clip.read().unwrap();
+		let idx = match ra_clip.fw_index(self.identity.lane) {
+			Some(x) => x as usize,
+			None => { return Vec::new(); }
+		};
+		let mut result: Vec<LaneIdentity> = Vec::new();
+		for j in [idx.wrapping_sub(1), idx + 1] {
+			let lane_fixed = match ra_clip.lanes_fixed.get(j) {
+				Some(x) => x,
+				None => { continue; }
+			};
+			for k in 0..lane_fixed.fw_count {
+				let identity = allocation.lane(lane_fixed.fw[k as usize]).read().unwrap().identity;
+				if identity.band == self.identity.band {
+					result.push(identity);
+				}
+			}
+		}
+		result
+	}
+
+	// The lanes around were told about the new lane while the clips were
+	// updated, this tells the new lane about them. Lanes created later link
+	// themselves the same way.
+	fn link(
+		allocation: &NetworkAllocation,
+		id: u32,
+		bw_lanes: &[u32],
+		fw_lanes: &[u32]
+	) {
+		let identities = |lanes: &[u32]| -> Vec<LaneIdentity> {
+			lanes.iter().map(
+				|x|
+				allocation.lane(*x).read().unwrap().identity
+			).collect()
+		};
+		let bw_identities = identities(bw_lanes);
+		let fw_identities = identities(fw_lanes);
+		let c_lane = allocation.lane(id);
+		let mut wa_lane = c_lane.write().unwrap();
+		wa_lane.bw_lanes.extend(bw_identities);
+		wa_lane.fw_lanes.extend(fw_identities);
+	}
+
+	// VEHICLES
+
+	// Index of the vehicle, searched outwards from where a vehicle at near
+	// would be.
+	fn vehicle_index(&self, sub: u32, near: f32) -> Option<usize> {
+		let start = self.vehicles.partition_point(
+			|x|
+			x.distance < near
+		);
+		let count = self.vehicles.len();
+		for offset in 0..=count {
+			if start + offset < count && self.vehicles[start + offset].identity.sub == sub {
+				return Some(start + offset);
+			}
+			if offset > 0 && offset <= start && self.vehicles[start - offset].identity.sub == sub {
+				return Some(start - offset);
+			}
+			if start + offset >= count && offset >= start {
+				break;
+			}
+		}
+		None
+	}
+
+	pub fn insert_vehicle(&mut self, vehicle: VehicleData) {
+		let idx = self.vehicles.partition_point(
+			|x|
+			x.distance <= vehicle.distance
+		);
+		self.vehicles.insert(idx, vehicle);
+	}
+
+	// near is where the vehicle is expected, usually its last distance.
+	pub fn remove_vehicle(&mut self, sub: u32, near: f32) -> Option<VehicleData> {
+		let idx = self.vehicle_index(sub, near)?;
+		Some(self.vehicles.remove(idx))
+	}
+
+	// Changes the vehicle and moves it back into order. Returns false when
+	// the vehicle is not on the lane.
+	pub fn update_vehicle<F>(&mut self, sub: u32, near: f32, update: F) -> bool
+	where
+		F: FnOnce(&mut VehicleData)
+	{
+		let mut idx = match self.vehicle_index(sub, near) {
+			Some(x) => x,
+			None => { return false; }
+		};
+		update(&mut self.vehicles[idx]);
+		while idx + 1 < self.vehicles.len() && self.vehicles[idx].distance > self.vehicles[idx + 1].distance {
+			self.vehicles.swap(idx, idx + 1);
+			idx += 1;
+		}
+		while idx > 0 && self.vehicles[idx].distance < self.vehicles[idx - 1].distance {
+			self.vehicles.swap(idx, idx - 1);
+			idx -= 1;
+		}
+		true
+	}
+
+	// Vehicles at or ahead of distance, closest first.
+	pub fn ahead(&self, distance: f32) -> &[VehicleData] {
+		let idx = self.vehicles.partition_point(
+			|x|
+			x.distance < distance
+		);
+		&self.vehicles[idx..]
+	}
+
+	// Closest vehicle at or ahead of distance other than exclude.
+	pub fn leader(&self, distance: f32, exclude: Option<u32>) -> Option<&VehicleData> {
+		self.ahead(distance).iter().find(
+			|x|
+			Some(x.identity.sub) != exclude
+		)
+	}
+
+	// Closest vehicle behind distance other than exclude.
+	pub fn follower(&self, distance: f32, exclude: Option<u32>) -> Option<&VehicleData> {
+		let idx = self.vehicles.partition_point(
+			|x|
+			x.distance < distance
+		);
+		self.vehicles[..idx].iter().rev().find(
+			|x|
+			Some(x.identity.sub) != exclude
+		)
+	}
+
+	// Vehicle furthest along the lane.
+	pub fn head(&self) -> Option<&VehicleData> {
+		self.vehicles.last()
+	}
+
+	// Vehicle that entered the lane last.
+	pub fn tail(&self) -> Option<&VehicleData> {
+		self.vehicles.first()
+	}
+}
+
+// Closest vehicle ahead of distance on lane, continuing onto the forward
+// lanes up to range. Its distance is the gap from distance to its rear. At a
+// fork the closest leader of all branches is taken.
+pub fn leader_of(
+	allocation: &NetworkAllocation,
+	lane: u32,
+	distance: f32,
+	exclude: Option<u32>,
+	range: f32
+) -> Option<VehicleData> {
+	let c_lane = allocation.lane(lane);
+	let ra_lane = c_lane.read().unwrap();
+	if let Some(x) = ra_lane.leader(distance, exclude) {
+		let gap = x.distance - x.length - distance;
+		return match gap <= range {
+			true => Some(VehicleData { distance: gap, ..*x }),
+			false => None
+		};
+	}
+	let passed = ra_lane.length - distance;
+	if passed >= range {
+		return None;
+	}
+	let fw_lanes: Vec<u32> = ra_lane.fw_lanes.iter().map(
+		|x|
+		x.lane
+	).collect();
+	drop(ra_lane);
+	drop(c_lane);
+	let mut result: Option<VehicleData> = None;
+	for fw_lane in fw_lanes {
+		if let Some(x) = leader_of(allocation, fw_lane, 0.0, exclude, range - passed) {
+			if result.is_none_or(|y| x.distance + passed < y.distance) {
+				result = Some(VehicleData { distance: x.distance + passed, ..x });
+			}
+		}
+	}
+	result
+}
+
+// Closest vehicle behind distance on lane, continuing onto the backward
+// lanes up to range. Its distance is the gap from its front to distance. At a
+// merge the closest follower of all branches is taken.
+pub fn follower_of(
+	allocation: &NetworkAllocation,
+	lane: u32,
+	distance: f32,
+	exclude: Option<u32>,
+	range: f32
+) -> Option<VehicleData> {
+	let c_lane = allocation.lane(lane);
+	let ra_lane = c_lane.read().unwrap();
+	if let Some(x) = ra_lane.follower(distance, exclude) {
+		let gap = distance - x.distance;
+		return match gap <= range {
+			true => Some(VehicleData { distance: gap, ..*x }),
+			false => None
+		};
+	}
+	if distance >= range {
+		return None;
+	}
+	let bw_lanes: Vec<u32> = ra_lane.bw_lanes.iter().map(
+		|x|
+		x.lane
+	).collect();
+	drop(ra_lane);
+	drop(c_lane);
+	let mut result: Option<VehicleData> = None;
+	for bw_lane in bw_lanes {
+		let length = allocation.lane(bw_lane).read().unwrap().length;
+		if let Some(x) = follower_of(allocation, bw_lane, length, exclude, range - distance) {
+			if result.is_none_or(|y| x.distance + distance < y.distance) {
+				result = Some(VehicleData { distance: x.distance + distance, ..x });
+			}
+		}
+	}
+	result
+}
+
+// Vehicles behind distance on lane and on all backward lanes up to range,
+// nearest first, exclude skipped. Each lane is searched once, along its
+// shortest way back.
+pub fn upstream_of(
+	allocation: &NetworkAllocation,
+	lane: u32,
+	distance: f32,
+	exclude: Option<u32>,
+	speed: f32,
+	range: f32
+) -> Vec<Upstream> {
+	let mut result: Vec<Upstream> = Vec::new();
+	let mut searched: HashSet<u32> = HashSet::new();
+	// Lane, distance to search back from and the gap already covered.
+	let mut pending: Vec<(u32, f32, f32)> = vec![(lane, distance, 0.0)];
+	while !pending.is_empty() {
+		let idx = pending.iter().enumerate().min_by(
+			|a, b|
+			a.1.2.partial_cmp(&b.1.2).expect("invalid distance")
+		).unwrap().0;
+		let (lane, from, covered) = pending.swap_remove(idx);
+		if !searched.insert(lane) {
+			continue;
+		}
+		let c_lane = allocation.lane(lane);
+		let ra_lane = c_lane.read().unwrap();
+		let end = ra_lane.vehicles.partition_point(
+			|x|
+			x.distance < from
+		);
+		for vehicle in ra_lane.vehicles[..end].iter().rev() {
+			let gap = covered + from - vehicle.distance;
+			if gap > range {
+				break;
+			}
+			if Some(vehicle.identity.sub) == exclude {
+				continue;
+			}
+			result.push(Upstream {
+				vehicle: *vehicle,
+				lane,
+				gap,
+				relative_speed: vehicle.speed - speed
+			});
+		}
+		if covered + from >= range {
+			continue;
+		}
+		for bw_lane in ra_lane.bw_lanes.iter() {
+			if searched.contains(&bw_lane.lane) {
+				continue;
+			}
+			let length = allocation.lane(bw_lane.lane).read().unwrap().length;
+			pending.push((bw_lane.lane, length, covered + from));
+		}
+	}
+	result.sort_by(
+		|a, b|
+		a.gap.partial_cmp(&b.gap).expect("invalid distance")
+	);
+
+	// A vehicle changing lanes is on two lanes, keep the nearer one.
+	let mut seen: HashSet<u32> = HashSet::new();
+	result.retain(
+		|x|
+		seen.insert(x.vehicle.identity.sub)
+	);
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, vehicle::{VehicleData, VehicleIdentity}};
+
+	use super::{Lane, LaneIdentity, leader_of, follower_of, upstream_of};
+
+	fn data(sub: u32, distance: f32) -> VehicleData {
+		VehicleData {
+			identity: VehicleIdentity {
+				sub,
+				..Default::default()
+			},
+			length: 4.0,
+			distance,
+			..Default::default()
+		}
+	}
+
+	fn order(lane: &Lane) -> Vec<u32> {
+		lane.vehicles.iter().map(
+			|x|
+			x.identity.sub
+		).collect()
+	}
+
+	#[test]
+	fn vehicles_stay_ordered() {
+		let (network, _) = testing::network();
+		let c_lane = network.allocation.lane(1);
+		let mut lane = c_lane.write().unwrap();
+		for (sub, distance) in [(1, 30.0), (2, 10.0), (3, 50.0), (4, 20.0)] {
+			lane.insert_vehicle(data(sub, distance));
+		}
+		assert_eq!(order(&lane), vec![2, 4, 1, 3]);
+		// Overtakes 1 and 3.
+		assert!(lane.update_vehicle(4, 20.0, |x| x.distance = 60.0));
+		assert_eq!(order(&lane), vec![2, 1, 3, 4]);
+		assert!(!lane.update_vehicle(9, 20.0, |x| x.distance = 0.0));
+		assert_eq!(lane.remove_vehicle(1, 0.0).map(|x| x.identity.sub), Some(1));
+		assert_eq!(order(&lane), vec![2, 3, 4]);
+		assert_eq!((lane.tail().unwrap().identity.sub, lane.head().unwrap().identity.sub), (2, 4));
+	}
+
+	#[test]
+	fn leader_and_follower_skip_the_vehicle() {
+		let (network, _) = testing::network();
+		let c_lane = network.allocation.lane(1);
+		let mut lane = c_lane.write().unwrap();
+		for (sub, distance) in [(1, 10.0), (2, 20.0), (3, 30.0)] {
+			lane.insert_vehicle(data(sub, distance));
+		}
+		assert_eq!(lane.leader(20.0, Some(2)).map(|x| x.identity.sub), Some(3));
+		assert_eq!(lane.follower(20.0, Some(2)).map(|x| x.identity.sub), Some(1));
+		assert_eq!(lane.leader(31.0, None).map(|x| x.identity.sub), None);
+		assert_eq!(lane.ahead(15.0).len(), 2);
+	}
+
+	#[test]
+	fn lanes_know_their_neighbours() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let links = |lane: u32| {
+			let c_lane = allocation.lane(lane);
+			let ra_lane = c_lane.read().unwrap();
+			let ids = |x: &Vec<LaneIdentity>| {
+				let mut ids: Vec<u32> = x.iter().map(|y| y.lane).collect();
+				ids.sort();
+				ids
+			};
+			(ids(&ra_lane.bw_lanes), ids(&ra_lane.fw_lanes))
+		};
+		// 3 was created after 1, which it continues.
+		assert_eq!(links(1), (vec![], vec![3]));
+		assert_eq!(links(3).0, vec![1]);
+		// Merge of 10 and 11.
+		assert_eq!(links(10).1, links(11).1);
+		assert_eq!(links(links(10).1[0]).0, vec![10, 11]);
+	}
+
+	#[test]
+	fn neighbours_across_lane_ends() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let length = allocation.lane(1).read().unwrap().length;
+		allocation.lane(3).write().unwrap().insert_vehicle(data(1, 14.0));
+		allocation.lane(1).write().unwrap().insert_vehicle(data(2, length - 20.0));
+		// 20 to the end of lane 1, then 10 to the rear of 1.
+		let leader = leader_of(allocation, 1, length - 20.0, Some(2), 100.0).unwrap();
+		assert_eq!((leader.identity.sub, leader.distance), (1, 30.0));
+		assert!(leader_of(allocation, 1, length - 20.0, Some(2), 25.0).is_none());
+		let follower = follower_of(allocation, 3, 10.0, Some(1), 100.0).unwrap();
+		assert_eq!((follower.identity.sub, follower.distance), (2, 30.0));
+	}
+
+	#[test]
+	fn upstream_continues_onto_every_backward_lane() {
+		let (network, _) = testing::network();
+		let allocation = &network.allocation;
+		let merged = allocation.lane(10).read().unwrap().fw_lanes[0].lane;
+		let length_a = allocation.lane(10).read().unwrap().length;
+		let length_b = allocation.lane(11).read().unwrap().length;
+		let place = |lane: u32, sub: u32, distance: f32, speed: f32| {
+			allocation.lane(lane).write().unwrap().insert_vehicle(VehicleData {
+				speed,
+				..data(sub, distance)
+			});
+		};
+		place(merged, 1, 20.0, 10.0);
+		place(merged, 2, 5.0, 12.0);
+		place(10, 3, length_a - 10.0, 8.0);
+		place(11, 4, length_b - 30.0, 10.0);
+		// Out of range.
+		place(11, 5, length_b - 60.0, 10.0);
+		// Changing from 10 to 11, on both lanes.
+		place(10, 6, length_a - 35.0, 10.0);
+		place(11, 6, length_b - 40.0, 10.0);
+		let upstream: Vec<(u32, u32, f32, f32)> = upstream_of(allocation, merged, 20.0, Some(1), 10.0, 60.0).iter().map(
+			|x|
+			(x.vehicle.identity.sub, x.lane, x.gap, x.relative_speed)
+		).collect();
+		assert_eq!(upstream, vec![
+			(2, merged, 15.0, 2.0),
+			(3, 10, 30.0, -2.0),
+			(4, 11, 50.0, 0.0),
+			(6, 10, 55.0, 0.0)
+		]);
+	}
+}
@@ -0,0 +1,276 @@
+use super::{following::Idm, lane::{Lane, LaneIdentity}, vehicle::{Vehicle, VehicleData}, NetworkAllocation, LANE_SPEED};
+
+// MOBIL lane changing. A vehicle changes to a lateral lane when the change
+// is safe for the follower there and the acceleration it gains outweighs
+// what its followers lose. Reaching a lane that continues the route is
+// mandatory and only has to be safe.
+#[derive(Debug, Clone, Copy)]
+pub struct LaneChangeConfig {
+	// Share of the followers' acceleration change weighed against the own.
+	// 0 is selfish, 1 is fully cooperative.
+	pub politeness: f32,
+	// Acceleration a discretionary change has to gain.
+	pub threshold: f32,
+	// Strongest deceleration a change may force on the vehicle or its new
+	// follower.
+	pub safe_decel: f32,
+	// Seconds the move from one lane to the other takes.
+	pub duration: f32,
+	// Seconds between two lane change decisions of a vehicle.
+	pub interval: f32,
+	// Accelerations are compared with this model. The pedal model keeps
+	// state, so it can not be asked about a lane it is not driving on.
+	pub model: Idm,
+}
+
+impl Default for LaneChangeConfig {
+	fn default() -> Self {
+		Self {
+			politeness: 0.2,
+			threshold: 2.0,
+			safe_decel: 40.0,
+			duration: 3.0,
+			interval: 1.0,
+			model: Idm::default()
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LaneChange {
+	// Lane being left. The vehicle stays in its vehicles until the change
+	// completes.
+	pub from: LaneIdentity,
+	// 0 at the start, 1 once fully on the active lane.
+	pub progress: f32,
+	// Side of the active lane the vehicle comes from, -1 or 1 in lanes_fixed
+	// order.
+	pub side: f32,
+}
+
+// Closest vehicles ahead of and behind distance on the lane other than
+// exclude.
+// Their distance is left along the lane.
+pub fn neighbours(
+	lane: &Lane,
+	distance: f32,
+	exclude: Option<u32>
+) -> (Option<VehicleData>, Option<VehicleData>) {
+	(lane.leader(distance, exclude).copied(), lane.follower(distance, exclude).copied())
+}
+
+// Lanes between identity and the closest lane of its band that continues
+// the route, 0 when identity does. None when no lane of the band does.
+pub fn lanes_to_route(
+	allocation: &NetworkAllocation,
+	vehicle: &Vehicle,
+	identity: LaneIdentity
+) -> Option<u8> {
+	let c_clip = allocation.clip(identity.clip);
+	let ra_clip = c_clip.read().unwrap();
+	let idx = ra_clip.fw_index(identity.lane)? as i16;
+	let mut result: Option<u8> = None;
+	for (j, lane_fixed) in ra_clip.lanes_fixed.iter().enumerate() {
+		for k in 0..lane_fixed.fw_count {
+			let lane = lane_fixed.fw[k as usize];
+			if !continues_route(allocation, vehicle, lane, identity.band) {
+				continue;
+			}
+			let diff = (j as i16 - idx).unsigned_abs() as u8;
+			if result.is_none_or(|x| diff < x) {
+				result = Some(diff);
+			}
+		}
+	}
+	result
+}
+
+fn continues_route(
+	allocation: &NetworkAllocation,
+	vehicle: &Vehicle,
+	lane: u32,
+	band: u32
+) -> bool {
+	let navigation = &vehicle.navigation;
+	let c_lane = allocation.lane(lane);
+	let ra_lane = c_lane.read().unwrap();
+	if ra_lane.identity.band != band {
+		return false;
+	}
+	if let Some(exit) = navigation.planned_exit(band) {
+		return lane == exit;
+	}
+	match navigation.nav_valid_band_lanes.get(navigation.active_nav as usize) {
+		Some(valid_lanes) => ra_lane.fw_lanes.iter().any(|x| valid_lanes.contains(&x.lane)),
+		None => match band == navigation.target_identity.band {
+			true => lane == navigation.target_identity.lane,
+			false => true
+		}
+	}
+}
+
+// Leader as seen from distance, for the following models.
+fn relative(
+	leader: Option<VehicleData>,
+	distance: f32
+) -> Option<VehicleData> {
+	leader.map(|x| VehicleData {
+		distance: x.distance - x.length - distance,
+		..x
+	})
+}
+
+// Lateral lane the vehicle should change to, if any.
+pub fn choose_lane(
+	allocation: &NetworkAllocation,
+	vehicle: &Vehicle,
+	config: &LaneChangeConfig
+) -> Option<LaneIdentity> {
+	let model = &config.model;
+	let speed = vehicle.data.speed;
+	let personality = &vehicle.driver_personality;
+	let desired_speed = (LANE_SPEED * personality.speed_compliance).min(vehicle.vehicle_type.max_speed);
+	// Aggressive drivers weigh their followers less and change sooner.
+	let politeness = config.politeness * (1.0 - personality.aggressiveness);
+	let threshold = config.threshold * (1.0 - 0.5 * personality.aggressiveness);
+	let sub = vehicle.data.identity.sub;
+
+	// CURRENT LANE
+
+	let c_lane = allocation.lane(vehicle.active_identity.lane);
+	let ra_lane = c_lane.read().unwrap();
+	let candidates = ra_lane.lateral_lanes(allocation);
+	let length = ra_lane.length;
+	let (leader, follower) = neighbours(&ra_lane, vehicle.data.distance, Some(sub));
+	drop(ra_lane);
+	drop(c_lane);
+	let own = model.acceleration_to(speed, desired_speed, relative(leader, vehicle.data.distance).as_ref());
+	let current_route = lanes_to_route(allocation, vehicle, vehicle.active_identity);
+
+	let mut best: Option<(bool, f32, LaneIdentity)> = None;
+	for candidate in candidates {
+
+		// ROUTE
+
+		// Never move away from the route.
+		let mandatory = match (current_route, lanes_to_route(allocation, vehicle, candidate)) {
+			(Some(current), Some(target)) if target > current => { continue; },
+			(Some(current), Some(target)) => target < current,
+			(Some(_), None) => { continue; },
+			(None, Some(_)) => true,
+			(None, None) => false
+		};
+
+		// SAFETY
+
+		let c_candidate = allocation.lane(candidate.lane);
+		let ra_candidate = c_candidate.read().unwrap();
+		let distance = vehicle.data.distance / length * ra_candidate.length;
+		if distance >= ra_candidate.length {
+			continue;
+		}
+		let (new_leader, new_follower) = neighbours(&ra_candidate, distance, Some(sub));
+		drop(ra_candidate);
+		drop(c_candidate);
+		let me = Some(VehicleData {
+			distance,
+			..vehicle.data
+		});
+		if new_leader.is_some_and(|x| x.distance - x.length - distance <= 0.0) ||
+			new_follower.is_some_and(|x| distance - vehicle.data.length - x.distance <= 0.0) {
+			continue;
+		}
+		let own_new = model.acceleration_to(speed, desired_speed, relative(new_leader, distance).as_ref());
+		let (new_follower_before, new_follower_after) = match new_follower {
+			Some(x) => (
+				model.acceleration_to(x.speed, LANE_SPEED, relative(new_leader, x.distance).as_ref()),
+				model.acceleration_to(x.speed, LANE_SPEED, relative(me, x.distance).as_ref())
+			),
+			None => (0.0, 0.0)
+		};
+		if own_new < -config.safe_decel || new_follower_after < -config.safe_decel {
+			continue;
+		}
+
+		// INCENTIVE
+
+		let (follower_before, follower_after) = match follower {
+			Some(x) => (
+				model.acceleration_to(x.speed, LANE_SPEED, relative(Some(vehicle.data), x.distance).as_ref()),
+				model.acceleration_to(x.speed, LANE_SPEED, relative(leader, x.distance).as_ref())
+			),
+			None => (0.0, 0.0)
+		};
+		let incentive = own_new - own + politeness * (
+			(new_follower_after - new_follower_before) + (follower_after - follower_before)
+		);
+		if !mandatory && incentive <= threshold {
+			continue;
+		}
+		if best.is_none_or(|x| (mandatory, incentive) > (x.0, x.1)) {
+			best = Some((mandatory, incentive, candidate));
+		}
+	}
+	best.map(|x| x.2)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::network::{testing, vehicle::{Vehicle, SpawnParams}, lane::LaneIdentity, Network};
+
+	use super::{choose_lane, lanes_to_route, LaneChangeConfig};
+
+	fn spawn(network: &Arc<Network>, src: LaneIdentity, dst: LaneIdentity, distance: f32, speed: f32) -> u32 {
+		Vehicle::spawn(network, src, dst, SpawnParams {
+			distance,
+			speed,
+			..Default::default()
+		}).unwrap().sub
+	}
+
+	fn choice(network: &Arc<Network>, sub: u32) -> Option<u32> {
+		let config = LaneChangeConfig::default();
+		testing::with_vehicle(network, sub, |x| choose_lane(&network.allocation, x, &config)).unwrap().map(
+			|x|
+			x.lane
+		)
+	}
+
+	#[test]
+	fn passes_a_standing_leader() {
+		let (network, lanes) = testing::network();
+		let sub = spawn(&network, lanes[6], lanes[12], 20.0, 50.0);
+		spawn(&network, lanes[6], lanes[12], 45.0, 0.0);
+		assert_eq!(choice(&network, sub), Some(lanes[7].lane));
+	}
+
+	#[test]
+	fn keeps_lane_without_incentive() {
+		let (network, lanes) = testing::network();
+		let sub = spawn(&network, lanes[6], lanes[12], 20.0, 50.0);
+		assert_eq!(choice(&network, sub), None);
+	}
+
+	#[test]
+	fn unsafe_gap_is_refused() {
+		let (network, lanes) = testing::network();
+		let sub = spawn(&network, lanes[6], lanes[12], 20.0, 50.0);
+		spawn(&network, lanes[6], lanes[12], 45.0, 0.0);
+		// Right next to the vehicle on both lateral lanes.
+		spawn(&network, lanes[7], lanes[12], 22.0, 50.0);
+		assert_eq!(choice(&network, sub), None);
+	}
+
+	#[test]
+	fn changes_to_reach_the_route() {
+		let (network, lanes) = testing::network();
+		// band_d only starts at lane 2.
+		let sub = spawn(&network, lanes[0], lanes[3], 20.0, 50.0);
+		let allocation = &network.allocation;
+		let to_route = testing::with_vehicle(&network, sub, |x| lanes_to_route(allocation, x, x.active_identity)).unwrap();
+		assert_eq!(to_route, Some(1));
+		assert_eq!(choice(&network, sub), Some(lanes[1].lane));
+	}
+}
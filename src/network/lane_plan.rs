@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet, BinaryHeap};
+
+use nalgebra::Vector2;
+
+use super::{lane::LaneIdentity, NetworkAllocation, hierarchy::QueueEntry};
+
+// Cost in distance units of changing to a lateral lane of the same band.
+pub const LANE_CHANGE_COST: f32 = 50.0;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LaneStep {
+	pub identity: LaneIdentity,
+	// Reached by changing lanes from the previous step, which is a lateral
+	// lane of the same band. Otherwise the lane is entered from the end of
+	// the previous step.
+	pub change: bool,
+}
+
+// A* over the lane graph. Lanes connect to their fw_lanes with the length of
+// the forward lane and to their lateral lanes with LANE_CHANGE_COST.
+pub fn plan_lanes(
+	allocation: &NetworkAllocation,
+	src_identity: LaneIdentity,
+	target_identity: LaneIdentity,
+	informed: bool,
+	time: f64
+) -> Option<Vec<LaneStep>> {
+	let focus_h: Vector2<f32> = allocation.lane(target_identity.lane).read().unwrap().p4;
+	let mut lane_g: HashMap<u32, f64> = HashMap::new();
+	let mut preceding: HashMap<u32, (u32, bool)> = HashMap::new();
+	let mut open: BinaryHeap<QueueEntry> = BinaryHeap::new();
+	let mut closed: HashSet<u32> = HashSet::new();
+
+	// INITIAL
+
+	lane_g.insert(src_identity.lane, 0.0);
+	open.push(QueueEntry {
+		cost: allocation.lane(src_identity.lane).read().unwrap().p4.metric_distance(&focus_h) as f64,
+		node: src_identity.lane as usize
+	});
+
+	while let Some(entry) = open.pop() {
+		let lane_min = entry.node as u32;
+		if !closed.insert(lane_min) {
+			continue;
+		}
+		if lane_min == target_identity.lane {
+			return Some(reconstruct(allocation, &preceding, src_identity, target_identity));
+		}
+
+		// BRANCH FROM CURRENT
+
+		let g_cost = lane_g[&lane_min];
+		let c_lane = allocation.lane(lane_min);
+		let ra_lane = c_lane.read().unwrap();
+		let band_min = ra_lane.identity.band;
+		let mut branches: Vec<(LaneIdentity, bool)> = ra_lane.fw_lanes.iter().map(|x| (*x, false)).collect();
+		branches.extend(ra_lane.lateral_lanes(allocation).into_iter().map(|x| (x, true)));
+		drop(ra_lane);
+		drop(c_lane);
+		for (identity, change) in branches {
+			if closed.contains(&identity.lane) {
+				continue;
+			}
+			if !change && identity.band != band_min {
+				let c_band = allocation.band(identity.band);
+				let ra_band = c_band.read().unwrap();
+				if ra_band.closed(time) {
+					continue;
+				}
+				if !allocation.clip(identity.clip).read().unwrap().transition_allowed(band_min, identity.band, time) {
+					continue;
+				}
+			}
+			let c_lane_branch = allocation.lane(identity.lane);
+			let ra_lane_branch = c_lane_branch.read().unwrap();
+			let edge_cost: f32 = match (change, informed) {
+				(true, _) => LANE_CHANGE_COST,
+				(false, true) => allocation.band(identity.band).read().unwrap().travel_cost(ra_lane_branch.length),
+				(false, false) => ra_lane_branch.length
+			};
+			let pos_g_cost: f64 = g_cost + edge_cost as f64;
+			if pos_g_cost < lane_g.get(&identity.lane).copied().unwrap_or(f64::INFINITY) {
+				lane_g.insert(identity.lane, pos_g_cost);
+				preceding.insert(identity.lane, (lane_min, change));
+				open.push(QueueEntry {
+					cost: pos_g_cost + ra_lane_branch.p4.metric_distance(&focus_h) as f64,
+					node: identity.lane as usize
+				});
+			}
+		}
+	}
+
+	None
+}
+
+fn reconstruct(
+	allocation: &NetworkAllocation,
+	preceding: &HashMap<u32, (u32, bool)>,
+	src_identity: LaneIdentity,
+	target_identity: LaneIdentity
+) -> Vec<LaneStep> {
+	let mut steps: Vec<LaneStep> = Vec::new();
+	let mut current: u32 = target_identity.lane;
+	while let Some((previous, change)) = preceding.get(&current) {
+		steps.insert(0, LaneStep {
+			identity: allocation.lane(current).read().unwrap().identity,
+			change: *change
+		});
+		current = *previous;
+	}
+	steps.insert(0, LaneStep {
+		identity: src_identity,
+		change: false
+	});
+	steps
+}
@@ -0,0 +1,281 @@
+use super::{vehicle::{Vehicle, VehicleData}, NetworkAllocation};
+
+#[derive(Debug, Clone, Copy)]
+pub enum MergeRule {
+	// Converging lanes take turns, one vehicle each.
+	Zipper,
+	// The vehicle arriving first goes. The later one goes as well if it
+	// arrives at least critical_gap seconds after it.
+	GapAcceptance {
+		critical_gap: f32
+	},
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MergeConfig {
+	pub rule: MergeRule,
+	// Distance before the merge point from which vehicles on converging
+	// lanes see each other.
+	pub awareness: f32,
+	// Deceleration used to tell if a vehicle can still stop before the
+	// merge point. A vehicle that can not goes first.
+	pub commit_decel: f32,
+}
+
+impl Default for MergeConfig {
+	fn default() -> Self {
+		Self {
+			rule: MergeRule::Zipper,
+			awareness: 100.0,
+			commit_decel: 50.0
+		}
+	}
+}
+
+// Negotiation state of a lanes_fixed entry with more than one backward
+// lane.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MergeState {
+	// Lane the last vehicle merged from.
+	pub last_lane: u32,
+	// Vehicle the others let go first. Decided once and kept until it
+	// merges, so vehicles ticked one after another agree on it.
+	pub priority: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Approach {
+	vehicle: VehicleData,
+	lane: u32,
+	// Distance left to the merge point.
+	remaining: f32,
+	arrival: f32,
+	committed: bool,
+}
+
+impl Approach {
+	fn new(
+		vehicle: VehicleData,
+		lane: u32,
+		remaining: f32,
+		config: &MergeConfig
+	) -> Self {
+		let speed = vehicle.speed;
+		Self {
+			vehicle,
+			lane,
+			remaining,
+			arrival: remaining / speed.max(0.1),
+			committed: remaining < speed * speed / (2.0 * config.commit_decel),
+		}
+	}
+
+	fn precedes(
+		&self,
+		other: &Approach,
+		state: MergeState,
+		config: &MergeConfig
+	) -> bool {
+		let earlier = (self.arrival, self.lane) < (other.arrival, other.lane);
+		if self.committed != other.committed {
+			return self.committed;
+		}
+		if self.committed {
+			return earlier;
+		}
+		match config.rule {
+			MergeRule::Zipper => {
+				if state.last_lane == other.lane && state.last_lane != self.lane {
+					return true;
+				}
+				if state.last_lane == self.lane && state.last_lane != other.lane {
+					return false;
+				}
+				earlier
+			},
+			MergeRule::GapAcceptance { .. } => earlier
+		}
+	}
+}
+
+// Vehicle the merging vehicle has to let go first, as a leader on its own
+// path. None when the active lane does not end in a merge or the vehicle
+// goes first. Only the first vehicle of each converging lane negotiates.
+pub fn merge_leader(
+	allocation: &NetworkAllocation,
+	vehicle: &Vehicle
+) -> Option<VehicleData> {
+	let config = *allocation.merge_config.read().unwrap();
+	let sub = vehicle.data.identity.sub;
+	let c_lane = allocation.lane(vehicle.active_identity.lane);
+	let ra_lane = c_lane.read().unwrap();
+	let remaining = ra_lane.length - vehicle.data.distance;
+	if remaining > config.awareness || ra_lane.vehicles.iter().rev().take_while(
+		|x|
+		x.distance > vehicle.data.distance
+	).any(
+		|x|
+		x.identity.sub != sub
+	) {
+		return None;
+	}
+	drop(ra_lane);
+	drop(c_lane);
+
+	// MERGE POINT
+
+	let dst_clip = allocation.band(vehicle.active_identity.band).read().unwrap().dst_clip;
+	let c_clip = allocation.clip(dst_clip);
+	let mut wa_clip = c_clip.write().unwrap();
+	let idx = wa_clip.bw_index(vehicle.active_identity.lane)?;
+	let lane_fixed = wa_clip.lanes_fixed[idx as usize].clone();
+	if lane_fixed.bw_count < 2 {
+		return None;
+	}
+
+	// APPROACHES
+
+	let own = Approach::new(vehicle.data, vehicle.active_identity.lane, remaining, &config);
+	let mut approaches: Vec<Approach> = vec![own];
+	for i in 0..lane_fixed.bw_count {
+		let lane = lane_fixed.bw[i as usize];
+		if lane == own.lane {
+			continue;
+		}
+		let c_other = allocation.lane(lane);
+		let ra_other = c_other.read().unwrap();
+		let head = ra_other.vehicles.iter().rev().find(
+			|x|
+			x.identity.sub != sub
+		);
+		if let Some(head) = head {
+			if ra_other.length - head.distance <= config.awareness {
+				approaches.push(Approach::new(*head, lane, ra_other.length - head.distance, &config));
+			}
+		}
+	}
+
+	// PRIORITY
+
+	let state = wa_clip.merge_states.entry(idx).or_default();
+	let priority = match state.priority.and_then(
+		|x|
+		approaches.iter().find(|y| y.vehicle.identity.sub == x)
+	) {
+		Some(x) => *x,
+		None => {
+			let mut first = approaches[0];
+			for approach in approaches.iter().skip(1) {
+				if approach.precedes(&first, *state, &config) {
+					first = *approach;
+				}
+			}
+			state.priority = Some(first.vehicle.identity.sub);
+			first
+		}
+	};
+	if priority.vehicle.identity.sub == sub {
+		return None;
+	}
+	if let MergeRule::GapAcceptance { critical_gap } = config.rule {
+		if own.arrival - priority.arrival >= critical_gap {
+			return None;
+		}
+	}
+
+	// Projected onto the own path. One still behind is waited for at the
+	// merge point.
+	let distance = own.remaining - priority.remaining - priority.vehicle.length;
+	match distance > 0.0 {
+		true => Some(VehicleData {
+			distance,
+			..priority.vehicle
+		}),
+		false => Some(VehicleData {
+			distance: own.remaining,
+			speed: 0.0,
+			..priority.vehicle
+		})
+	}
+}
+
+// Called when a vehicle moves from lane onto fw_lane.
+pub fn record_merge(
+	allocation: &NetworkAllocation,
+	lane: u32,
+	fw_lane: u32
+) {
+	let src_clip = allocation.lane(fw_lane).read().unwrap().identity.clip;
+	let c_clip = allocation.clip(src_clip);
+	let mut wa_clip = c_clip.write().unwrap();
+	let idx = match wa_clip.bw_index(lane) {
+		Some(x) => x,
+		None => { return; }
+	};
+	if wa_clip.lanes_fixed[idx as usize].bw_count < 2 {
+		return;
+	}
+	wa_clip.merge_states.insert(idx, MergeState {
+		last_lane: lane,
+		priority: None
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::network::{testing, vehicle::{Vehicle, SpawnParams}, lane::LaneIdentity, Network};
+
+	use super::{merge_leader, record_merge, MergeRule};
+
+	// Vehicle remaining short of the end of the lane.
+	fn spawn(network: &Arc<Network>, src: LaneIdentity, dst: LaneIdentity, remaining: f32) -> u32 {
+		let length = network.allocation.lane(src.lane).read().unwrap().length;
+		Vehicle::spawn(network, src, dst, SpawnParams {
+			distance: length - remaining,
+			speed: 30.0,
+			..Default::default()
+		}).unwrap().sub
+	}
+
+	fn leader(network: &Arc<Network>, sub: u32) -> Option<u32> {
+		testing::with_vehicle(network, sub, |x| merge_leader(&network.allocation, x)).unwrap().map(
+			|x|
+			x.identity.sub
+		)
+	}
+
+	#[test]
+	fn first_arrival_goes_first() {
+		// lane_m and lane_n merge into band_f's first lane.
+		let (network, lanes) = testing::network();
+		let a = spawn(&network, lanes[9], lanes[12], 50.0);
+		let b = spawn(&network, lanes[10], lanes[12], 60.0);
+		assert_eq!(leader(&network, b), Some(a));
+		assert_eq!(leader(&network, a), None);
+	}
+
+	#[test]
+	fn zipper_takes_turns() {
+		let (network, lanes) = testing::network();
+		let a = spawn(&network, lanes[9], lanes[12], 50.0);
+		let b = spawn(&network, lanes[10], lanes[12], 60.0);
+		// A vehicle of lane_m just merged, lane_n is next.
+		record_merge(&network.allocation, lanes[9].lane, lanes[12].lane);
+		assert_eq!(leader(&network, a), Some(b));
+		assert_eq!(leader(&network, b), None);
+	}
+
+	#[test]
+	fn gap_acceptance_lets_late_vehicles_go() {
+		let (network, lanes) = testing::network();
+		network.allocation.merge_config.write().unwrap().rule = MergeRule::GapAcceptance {
+			critical_gap: 1.0
+		};
+		let a = spawn(&network, lanes[9], lanes[12], 10.0);
+		let b = spawn(&network, lanes[10], lanes[12], 80.0);
+		assert_eq!(leader(&network, b), None);
+		assert_eq!(leader(&network, a), None);
+	}
+}
@@ -2,7 +2,7 @@ use std::collections::BTreeMap;
 
 use nalgebra::Vector2;
 
-use super::{lane::LaneIdentity, NetworkAllocation, band::BandIdentity};
+use super::{lane::LaneIdentity, NetworkAllocation, band::BandIdentity, lane_plan::{LaneStep, plan_lanes}, alternatives::RouteChoice};
 
 #[derive(Debug, Clone)]
 pub struct ForwardLane {
@@ -23,12 +23,36 @@ pub struct Point {
 	pub accumulated_distance: f32,
 }
 
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Waypoint {
+	pub identity: LaneIdentity,
+	// Distance into the lane the waypoint is reached at.
+	pub distance: f32,
+	// Seconds the vehicle stands still once the waypoint is reached.
+	pub dwell: f32,
+}
+
 #[derive(Debug, Default)]
 pub struct Navigation {
 	pub active_nav: u16,
 	pub nav: Vec<BandIdentity>,
 	pub nav_valid_band_lanes: Vec<Vec<u32>>,
 	pub target_identity: LaneIdentity,
+	// Route with the bands' measured travel times instead of their length.
+	pub informed: bool,
+	// Stops visited in order. The target is the active waypoint while there
+	// are waypoints left.
+	pub waypoints: Vec<Waypoint>,
+	pub active_waypoint: usize,
+	// Plan over lanes instead of bands. The lane changes then head for the
+	// lanes of lane_plan.
+	pub lane_routing: bool,
+	pub lane_plan: Vec<LaneStep>,
+	// Pick between alternative band paths instead of always the shortest.
+	pub route_choice: Option<RouteChoice>,
+	// Bands to drive through in order instead of searching a route. Each
+	// leg takes the part from the active to the target band.
+	pub fixed_path: Vec<u32>,
 }
 
 impl Navigation {
@@ -36,6 +60,33 @@ impl Navigation {
 		self.active_nav = 0;
 		self.nav.clear();
 		self.nav_valid_band_lanes.clear();
+		self.lane_plan.clear();
+	}
+
+	pub fn waypoint(&self) -> Option<&Waypoint> {
+		self.waypoints.get(self.active_waypoint)
+	}
+
+	// Advances to the next waypoint and routes the leg to it. Returns false
+	// when there are no waypoints left or the leg has no route.
+	pub fn next_leg(
+		&mut self,
+		allocation: &NetworkAllocation,
+		active_identity: LaneIdentity
+	) -> bool {
+		self.active_waypoint += 1;
+		let waypoint = match self.waypoint() {
+			Some(x) => *x,
+			None => { return false; }
+		};
+		self.target_identity = waypoint.identity;
+		if active_identity.band == waypoint.identity.band {
+			// The waypoint is further along the active band. Without a nav
+			// the lane changes head for the target lane.
+			self.reset_nav();
+			return true;
+		}
+		self.renavigate(allocation, active_identity)
 	}
 
 	pub fn renavigate(
@@ -50,7 +101,89 @@ impl Navigation {
 			return false;
 		}
 		// println!("renav from:\n{:?}\nto:\n{:?}", active_identity, self.target_identity);
+		let mut current: Vec<u32> = vec![active_identity.band];
+		current.extend(self.nav.iter().skip(self.active_nav as usize).map(
+			|x|
+			x.band
+		));
 		self.reset_nav();
+		let time = allocation.time();
+
+		// LANE PLAN
+
+		if self.lane_routing {
+			return match plan_lanes(allocation, active_identity, self.target_identity, self.informed, time) {
+				Some(plan) => {
+					self.update_nav_lanes(allocation, plan, &active_identity);
+					true
+				},
+				None => false
+			};
+		}
+
+		// FIXED PATH
+
+		if !self.fixed_path.is_empty() {
+			let path = self.fixed_path.iter().position(
+				|x|
+				*x == active_identity.band
+			).and_then(
+				|x|
+				{
+					let rest = &self.fixed_path[x..];
+					rest.iter().position(|y| *y == self.target_identity.band).map(|y| rest[..=y].to_vec())
+				}
+			);
+			return match path {
+				Some(path) if self.target_reachable(allocation, path[path.len() - 2]) => {
+					let mut preceding: BTreeMap<u32, u32> = BTreeMap::new();
+					for pair in path.windows(2) {
+						preceding.insert(pair[1], pair[0]);
+					}
+					self.update_nav(allocation, &preceding, &active_identity);
+					true
+				},
+				_ => false
+			};
+		}
+
+		// ROUTE CHOICE
+
+		if let Some(route_choice) = self.route_choice {
+			if let Some(path) = route_choice.choose(allocation, self, active_identity.band, &current) {
+				let mut preceding: BTreeMap<u32, u32> = BTreeMap::new();
+				for pair in path.windows(2) {
+					preceding.insert(pair[1], pair[0]);
+				}
+				self.update_nav(allocation, &preceding, &active_identity);
+				return true;
+			}
+		}
+
+		// HIERARCHY
+
+		// The hierarchy only knows band lengths, so informed vehicles keep
+		// using A* with travel times.
+		let hierarchy = match self.informed {
+			true => None,
+			false => allocation.hierarchy.read().unwrap().clone()
+		};
+		if let Some(hierarchy) = hierarchy {
+			if let Some((path, _)) = hierarchy.path(active_identity.band, self.target_identity.band) {
+				// Restrictions and closures are not part of the hierarchy, a
+				// blocked path falls back to A*.
+				if self.target_reachable(allocation, path[path.len() - 2]) &&
+					!Self::path_blocked(allocation, &path, time) {
+					let mut preceding: BTreeMap<u32, u32> = BTreeMap::new();
+					for pair in path.windows(2) {
+						preceding.insert(pair[1], pair[0]);
+					}
+					self.update_nav(allocation, &preceding, &active_identity);
+					return true;
+				}
+			}
+		}
+
 		let focus_h: Vector2<f32> = allocation.lane(self.target_identity.lane).read().unwrap().p4;
 		let mut open_gf: Vec<u32> = Vec::new();
 		let mut band_gf: BTreeMap<u32, GFCost> = BTreeMap::new();
@@ -85,24 +218,7 @@ impl Navigation {
 			let band_min = *min_cost.1;
 			if band_min == self.target_identity.band {
 				let preceding_band_id = preceding_gf[&band_min];
-				let c_band_prev = allocation.band(preceding_band_id);
-				let ra_band_prev = c_band_prev.read().unwrap();
-				let c_clip_prev = allocation.clip(ra_band_prev.dst_clip);
-				let ra_clip_prev = c_clip_prev.read().unwrap();
-				let lf_target_lane = ra_clip_prev.lanes_fixed.iter().enumerate().find(
-					|x|
-					{
-						let f = x.1;
-						for i in 0..f.fw_count {
-							if f.fw[i as usize] == self.target_identity.lane {
-								return true;
-							}
-						}
-						false
-					}
-				).expect("target lane does not exist");
-				if (lf_target_lane.0 as u8) >= ra_band_prev.dst_min &&
-					(lf_target_lane.0 as u8) <= ra_band_prev.dst_max {
+				if self.target_reachable(allocation, preceding_band_id) {
 					self.update_nav(&allocation, &preceding_gf, &active_identity);
 					return true;
 				}
@@ -122,10 +238,19 @@ impl Navigation {
 			let ra_clip_fw = c_clip_fw.read().unwrap();
 			// let current_gf_cost = band_gf_costs[current_band_gf as usize];
 			for i in ra_clip_fw.fw_bands.iter() {
-				let band_lane_idx = allocation.band(*i).read().unwrap().src_min;
+				let c_band_fw = allocation.band(*i);
+				let ra_band_fw = c_band_fw.read().unwrap();
+				if ra_band_fw.closed(time) || !ra_clip_fw.transition_allowed(band_min, *i, time) {
+					continue;
+				}
+				let band_lane_idx = ra_band_fw.src_min;
 				let c_lane_band = allocation.lane(ra_clip_fw.lanes_fixed[band_lane_idx as usize].fw[0]);
 				let ra_lane_band = c_lane_band.read().unwrap();
-				let pos_g_cost: f64 = gf.g_cost + ra_lane_band.length as f64;
+				let band_cost: f32 = match self.informed {
+					true => ra_band_fw.travel_cost(ra_lane_band.length),
+					false => ra_lane_band.length
+				};
+				let pos_g_cost: f64 = gf.g_cost + band_cost as f64;
 				let fw_band_g_cost: f64 = match band_gf.get(i) {
 					Some(x) => x.g_cost,
 					None => f64::INFINITY
@@ -156,6 +281,64 @@ impl Navigation {
 		return false;
 	}
 
+	// Whether a band of the path is closed or a transition between two of
+	// its bands is restricted. The first band is the one being driven on.
+	fn path_blocked(
+		allocation: &NetworkAllocation,
+		path: &[u32],
+		time: f64
+	) -> bool {
+		for pair in path.windows(2) {
+			let c_band = allocation.band(pair[1]);
+			let ra_band = c_band.read().unwrap();
+			if ra_band.closed(time) {
+				return true;
+			}
+			if !allocation.clip(ra_band.src_clip).read().unwrap().transition_allowed(pair[0], pair[1], time) {
+				return true;
+			}
+		}
+		false
+	}
+
+	// Whether the remaining route got closed or restricted since it was
+	// planned.
+	pub fn route_blocked(
+		&self,
+		allocation: &NetworkAllocation,
+		active_identity: &LaneIdentity
+	) -> bool {
+		let mut path: Vec<u32> = vec![active_identity.band];
+		path.extend(self.nav.iter().skip(self.active_nav as usize).map(|x| x.band));
+		Self::path_blocked(allocation, &path, allocation.time())
+	}
+
+	// Whether the target lane can be entered from the preceding band.
+	pub(crate) fn target_reachable(
+		&self,
+		allocation: &NetworkAllocation,
+		preceding_band_id: u32
+	) -> bool {
+		let c_band_prev = allocation.band(preceding_band_id);
+		let ra_band_prev = c_band_prev.read().unwrap();
+		let c_clip_prev = allocation.clip(ra_band_prev.dst_clip);
+		let ra_clip_prev = c_clip_prev.read().unwrap();
+		let lf_target_lane = ra_clip_prev.lanes_fixed.iter().enumerate().find(
+			|x|
+			{
+				let f = x.1;
+				for i in 0..f.fw_count {
+					if f.fw[i as usize] == self.target_identity.lane {
+						return true;
+					}
+				}
+				false
+			}
+		).expect("target lane does not exist");
+		(lf_target_lane.0 as u8) >= ra_band_prev.dst_min &&
+			(lf_target_lane.0 as u8) <= ra_band_prev.dst_max
+	}
+
 	fn update_nav(
 		&mut self,
 		allocation: &NetworkAllocation,
@@ -177,7 +360,7 @@ impl Navigation {
 				Some(x) => *x,
 				None => { break; },
 			};
-			if current == active_identity.lane {
+			if current == active_identity.band {
 				break;
 			}
 		}
@@ -214,6 +397,39 @@ impl Navigation {
 		}
 	}
 
+	// The bands of the plan become the nav, so the valid lanes stay the ones
+	// leading into the next band. Which lane of a band to head for is taken
+	// from the plan, see planned_exit.
+	fn update_nav_lanes(
+		&mut self,
+		allocation: &NetworkAllocation,
+		plan: Vec<LaneStep>,
+		active_identity: &LaneIdentity
+	) {
+		let mut preceding: BTreeMap<u32, u32> = BTreeMap::new();
+		let mut band: u32 = active_identity.band;
+		for step in plan.iter() {
+			if step.identity.band != band {
+				preceding.insert(step.identity.band, band);
+				band = step.identity.band;
+			}
+		}
+		self.update_nav(allocation, &preceding, active_identity);
+		self.lane_plan = plan;
+	}
+
+	// Lane the plan leaves the band from, None when the plan does not pass
+	// the band.
+	pub fn planned_exit(&self, band: u32) -> Option<u32> {
+		self.lane_plan.iter().rev().find(
+			|x|
+			x.identity.band == band
+		).map(
+			|x|
+			x.identity.lane
+		)
+	}
+
 	pub fn get_forward_lanes(
 		&self,
 		allocation: &NetworkAllocation,
@@ -265,4 +481,53 @@ impl Navigation {
 		}
 		result
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::testing;
+
+	use super::{Navigation, Waypoint};
+
+	#[test]
+	fn next_leg_on_same_band() {
+		let (network, lanes) = testing::network();
+		let mut navigation = Navigation {
+			target_identity: lanes[0],
+			waypoints: vec![
+				Waypoint { identity: lanes[0], distance: 5.0, dwell: 0.0 },
+				Waypoint { identity: lanes[1], distance: 140.0, dwell: 0.0 }
+			],
+			..Default::default()
+		};
+		assert!(navigation.next_leg(&network.allocation, lanes[0]));
+		assert_eq!(navigation.target_identity.lane, lanes[1].lane);
+		assert!(navigation.nav.is_empty());
+		assert!(!navigation.next_leg(&network.allocation, lanes[1]));
+	}
+
+	#[test]
+	fn lane_plan_keeps_valid_lanes_reachable() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let mut navigation = Navigation {
+			target_identity: lanes[11],
+			lane_routing: true,
+			..Default::default()
+		};
+		assert!(navigation.renavigate(allocation, lanes[0]));
+		let bands: Vec<u32> = navigation.nav.iter().map(
+			|x|
+			x.band
+		).collect();
+		assert_eq!(bands, vec![4, 5, 6]);
+		// The plan changes to lane 2 first, its forward lanes follow the nav.
+		assert_eq!(navigation.planned_exit(1), Some(2));
+		assert_eq!(navigation.planned_exit(5), Some(9));
+		let forward: Vec<u32> = navigation.get_forward_lanes(allocation, 1_000.0, 2).iter().map(
+			|x|
+			x.id
+		).collect();
+		assert_eq!(forward, vec![4, 9, 12]);
+	}
+}
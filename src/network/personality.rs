@@ -0,0 +1,142 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use super::{vehicle::DriverPersonality, vehicle_type::VehicleType, NetworkAllocation};
+
+// Distribution of a personality value. Draws come from the network rng, so
+// seeded runs spawn the same drivers.
+#[derive(Debug, Clone, Copy)]
+pub enum Distribution {
+	Fixed(f32),
+	Uniform {
+		min: f32,
+		max: f32
+	},
+	// Clamped to min and max.
+	Normal {
+		mean: f32,
+		deviation: f32,
+		min: f32,
+		max: f32
+	},
+}
+
+impl Distribution {
+	pub fn sample(&self, allocation: &NetworkAllocation) -> f32 {
+		match *self {
+			Distribution::Fixed(x) => x,
+			Distribution::Uniform { min, max } => {
+				if max <= min {
+					return min;
+				}
+				allocation.rng.write().unwrap().0.gen_range(min..max)
+			},
+			Distribution::Normal { mean, deviation, min, max } => {
+				// Box-Muller transform.
+				let mut rng = allocation.rng.write().unwrap();
+				let u1: f32 = 1.0 - rng.0.gen::<f32>();
+				let u2: f32 = rng.0.gen::<f32>();
+				let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+				(mean + deviation * z).clamp(min, max)
+			}
+		}
+	}
+}
+
+// Distributions the drivers of a vehicle type are drawn from.
+#[derive(Debug, Clone, Copy)]
+pub struct PersonalityProfile {
+	// Share of the vehicle type's max accel and max decel the driver is
+	// willing to use.
+	pub accel_share: Distribution,
+	pub decel_share: Distribution,
+	// Desired seconds to the leader.
+	pub time_headway: Distribution,
+	// Desired speed as a multiple of the lane speed.
+	pub speed_compliance: Distribution,
+	// Seconds before the driver reacts.
+	pub reaction_time: Distribution,
+	// Between 0 and 1. Aggressive drivers care less about their followers
+	// when changing lanes.
+	pub aggressiveness: Distribution,
+	// Seconds, see DriverPersonality.
+	pub critical_gap: Distribution,
+	pub follow_up_time: Distribution,
+}
+
+impl Default for PersonalityProfile {
+	fn default() -> Self {
+		Self {
+			accel_share: Distribution::Normal { mean: 0.9, deviation: 0.1, min: 0.6, max: 1.0 },
+			decel_share: Distribution::Normal { mean: 0.9, deviation: 0.1, min: 0.6, max: 1.0 },
+			time_headway: Distribution::Normal { mean: 1.2, deviation: 0.3, min: 0.6, max: 2.5 },
+			speed_compliance: Distribution::Normal { mean: 1.0, deviation: 0.1, min: 0.8, max: 1.2 },
+			reaction_time: Distribution::Normal { mean: 0.8, deviation: 0.2, min: 0.4, max: 1.5 },
+			aggressiveness: Distribution::Uniform { min: 0.0, max: 1.0 },
+			critical_gap: Distribution::Normal { mean: 6.5, deviation: 0.8, min: 4.0, max: 9.0 },
+			follow_up_time: Distribution::Normal { mean: 3.3, deviation: 0.4, min: 2.0, max: 5.0 }
+		}
+	}
+}
+
+impl PersonalityProfile {
+	pub fn sample(
+		&self,
+		allocation: &NetworkAllocation,
+		vehicle_type: &VehicleType
+	) -> DriverPersonality {
+		DriverPersonality {
+			willing_max_accel: vehicle_type.max_accel * self.accel_share.sample(allocation),
+			willing_max_decel: vehicle_type.max_decel * self.decel_share.sample(allocation),
+			time_headway: self.time_headway.sample(allocation),
+			speed_compliance: self.speed_compliance.sample(allocation),
+			reaction_time: self.reaction_time.sample(allocation),
+			aggressiveness: self.aggressiveness.sample(allocation).clamp(0.0, 1.0),
+			critical_gap: self.critical_gap.sample(allocation),
+			follow_up_time: self.follow_up_time.sample(allocation)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{vehicle_type::VehicleType, NetworkAllocation};
+
+	use super::{Distribution, PersonalityProfile};
+
+	#[test]
+	fn samples_stay_in_bounds() {
+		let allocation = NetworkAllocation::default();
+		let normal = Distribution::Normal { mean: 1.0, deviation: 2.0, min: 0.5, max: 1.5 };
+		let uniform = Distribution::Uniform { min: 2.0, max: 3.0 };
+		let mut sum: f32 = 0.0;
+		for _ in 0..1_000 {
+			let x = normal.sample(&allocation);
+			assert!((0.5..=1.5).contains(&x));
+			let y = uniform.sample(&allocation);
+			assert!((2.0..3.0).contains(&y));
+			sum += y;
+		}
+		assert!((sum / 1_000.0 - 2.5).abs() < 0.05);
+		assert_eq!(Distribution::Fixed(4.0).sample(&allocation), 4.0);
+	}
+
+	#[test]
+	fn seeded_drivers_repeat() {
+		let drivers = || {
+			let allocation = NetworkAllocation::default();
+			allocation.seed(11);
+			let profile = PersonalityProfile::default();
+			let vehicle_type = VehicleType::default();
+			(0..5).map(
+				|_|
+				profile.sample(&allocation, &vehicle_type).reaction_time
+			).collect::<Vec<f32>>()
+		};
+		assert_eq!(drivers(), drivers());
+		let vehicle_type = VehicleType::default();
+		let driver = PersonalityProfile::default().sample(&NetworkAllocation::default(), &vehicle_type);
+		assert!(driver.willing_max_decel <= vehicle_type.max_decel);
+	}
+}
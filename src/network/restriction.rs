@@ -0,0 +1,86 @@
+use super::DAY_SECONDS;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TimeWindow {
+	pub start: f64,
+	pub end: f64,
+	// Repeats every day, start and end are then seconds of the day. A start
+	// after the end wraps over midnight.
+	pub daily: bool,
+}
+
+impl TimeWindow {
+	pub fn always() -> Self {
+		Self {
+			start: f64::NEG_INFINITY,
+			end: f64::INFINITY,
+			daily: false
+		}
+	}
+
+	pub fn contains(&self, time: f64) -> bool {
+		if !self.daily {
+			return time >= self.start && time < self.end;
+		}
+		let time_of_day = time.rem_euclid(DAY_SECONDS);
+		if self.start <= self.end {
+			time_of_day >= self.start && time_of_day < self.end
+		} else {
+			time_of_day >= self.start || time_of_day < self.end
+		}
+	}
+}
+
+// Forbids entering to_band from from_band at the clip holding the rule while
+// the window is active.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TurnRestriction {
+	pub from_band: u32,
+	pub to_band: u32,
+	pub window: TimeWindow,
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, navigation::Navigation, DAY_SECONDS};
+
+	use super::{TimeWindow, TurnRestriction};
+
+	#[test]
+	fn daily_window_wraps_midnight() {
+		let window = TimeWindow {
+			start: 22.0 * 3_600.0,
+			end: 6.0 * 3_600.0,
+			daily: true
+		};
+		assert!(window.contains(23.0 * 3_600.0));
+		assert!(window.contains(DAY_SECONDS + 3_600.0));
+		assert!(!window.contains(12.0 * 3_600.0));
+		let once = TimeWindow {
+			start: 10.0,
+			end: 20.0,
+			daily: false
+		};
+		assert!(once.contains(10.0));
+		assert!(!once.contains(20.0));
+		assert!(!once.contains(DAY_SECONDS + 15.0));
+	}
+
+	#[test]
+	fn restricted_turn_is_avoided() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		// band_a to band_b at clip b.
+		allocation.clip(2).write().unwrap().restrictions.push(TurnRestriction {
+			from_band: 1,
+			to_band: 2,
+			window: TimeWindow::always()
+		});
+		let mut navigation = Navigation {
+			target_identity: lanes[12],
+			..Default::default()
+		};
+		assert!(navigation.renavigate(allocation, lanes[0]));
+		assert_eq!(navigation.nav[0].band, 4);
+	}
+}
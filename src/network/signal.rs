@@ -1,8 +1,9 @@
 use core::fmt;
+use std::collections::HashSet;
 
 use async_trait::async_trait;
 
-use super::{vehicle::{VehicleData, Vehicle, VTarget}, NetworkAllocation};
+use super::{vehicle::{VehicleData, Vehicle, VTarget}, lane::upstream_of, NetworkAllocation};
 
 // Signals are default positioned at the end of the lane. Increasing
 // activation_distance will bring the activation point backward into the lane.
@@ -19,7 +20,10 @@ pub struct SignalIdentity {
 #[derive(Debug, Default, Copy, Clone)]
 pub struct InstructSlow {
 	pub target_speed: f32,
-	pub target: VTarget
+	pub target: VTarget,
+	// Distance to a line the vehicle has to stop at. Drivers keep their gap
+	// to it as to a standing vehicle.
+	pub stop_distance: Option<f32>,
 }
 
 pub enum InstructResult {
@@ -91,10 +95,496 @@ impl Signal for FullStop {
 			let percent = 1.0 - ((self.vehicle_init_distance - stop_line_distance) / self.vehicle_init_distance);
 			return InstructResult::SLOW(InstructSlow {
 				target_speed: percent * self.vehicle_init_speed,
-				target: VTarget::DecTStop
+				target: VTarget::DecTStop,
+				stop_distance: None
 			});
 		// }
 		// println!("*****MORE THAN 3 SEC*****");
 		// InstructResult::KEEP
 	}
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightState {
+	Green,
+	Yellow,
+	Red,
+}
+
+// Fixed time plan of a light. Green, yellow and red follow each other, the
+// cycle is their sum.
+#[derive(Debug, Clone, Copy)]
+pub struct PhasePlan {
+	pub green: f32,
+	pub yellow: f32,
+	pub red: f32,
+	// Seconds into the cycle at simulation time 0, used to coordinate
+	// lights.
+	pub offset: f32,
+}
+
+impl Default for PhasePlan {
+	fn default() -> Self {
+		Self {
+			green: 30.0,
+			yellow: 4.0,
+			red: 26.0,
+			offset: 0.0
+		}
+	}
+}
+
+impl PhasePlan {
+	pub fn cycle(&self) -> f32 {
+		self.green + self.yellow + self.red
+	}
+
+	// State at time and the seconds until it changes.
+	pub fn state(&self, time: f64) -> (LightState, f32) {
+		let cycle = self.cycle() as f64;
+		if cycle <= 0.0 {
+			return (LightState::Green, f32::INFINITY);
+		}
+		let t = (time + self.offset as f64).rem_euclid(cycle) as f32;
+		if t < self.green {
+			return (LightState::Green, self.green - t);
+		}
+		if t < self.green + self.yellow {
+			return (LightState::Yellow, self.green + self.yellow - t);
+		}
+		(LightState::Red, self.cycle() - t)
+	}
+}
+
+// Distance from the vehicle's front to the signal, None once it is neither
+// on the active nor on a forward lane.
+pub fn stop_line_distance(
+	allocation: &NetworkAllocation,
+	vehicle: &Vehicle,
+	identity: &SignalIdentity
+) -> Option<f32> {
+	if identity.lane == vehicle.active_identity.lane {
+		return Some(identity.signal_distance - vehicle.data.distance);
+	}
+	vehicle.distance_from_fw(allocation, identity.signal_distance, identity.lane)
+}
+
+// Stops at the line on red and yellow if the vehicle can do so with its
+// willing deceleration, on yellow only when it would not reach the line
+// before red either. Otherwise it goes, it committed to crossing. Both count
+// with the distance driven before the driver reacts, the instruction reaches
+// the vehicle that much later.
+pub fn light_instruct(
+	allocation: &NetworkAllocation,
+	vehicle: &Vehicle,
+	identity: &SignalIdentity,
+	state: LightState,
+	remaining: f32
+) -> InstructResult {
+	let distance = match stop_line_distance(allocation, vehicle, identity) {
+		Some(x) if x >= 0.0 => x,
+		_ => { return InstructResult::DESTROY; }
+	};
+	let decel = vehicle.driver_personality.willing_max_decel;
+	let speed = vehicle.data.speed;
+	let braking = distance - speed * vehicle.driver_personality.reaction_time;
+	let can_stop = speed * speed / (2.0 * decel) <= braking;
+	let stop = match state {
+		LightState::Green => false,
+		LightState::Red => can_stop,
+		LightState::Yellow => can_stop && distance > speed * remaining
+	};
+	if !stop {
+		return InstructResult::KEEP;
+	}
+	stop_instruct(vehicle, distance)
+}
+
+// Brings the vehicle to a stop distance ahead.
+pub fn stop_instruct(
+	vehicle: &Vehicle,
+	distance: f32
+) -> InstructResult {
+	let decel = vehicle.driver_personality.willing_max_decel;
+	let braking = distance - vehicle.data.speed * vehicle.driver_personality.reaction_time;
+	// Speed from which half the willing deceleration stops the vehicle at
+	// the line.
+	InstructResult::SLOW(InstructSlow {
+		target_speed: (braking.max(0.0) * decel).sqrt(),
+		target: VTarget::DecTStop,
+		stop_distance: Some(distance)
+	})
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrafficLight {
+	pub signal_identity: SignalIdentity,
+	pub plan: PhasePlan,
+}
+
+impl TrafficLight {
+	pub fn state(&self, allocation: &NetworkAllocation) -> (LightState, f32) {
+		self.plan.state(allocation.time())
+	}
+}
+
+#[async_trait]
+impl Signal for TrafficLight {
+	fn identity(&self) -> &SignalIdentity {
+		&self.signal_identity
+	}
+
+	fn identity_mut(&mut self) -> &mut SignalIdentity {
+		&mut self.signal_identity
+	}
+
+	fn activate(&mut self,
+		_allocation: &NetworkAllocation,
+		_vehicle: &Vehicle
+	) {}
+
+	fn instruct(&mut self,
+		allocation: &NetworkAllocation,
+		vehicle: &Vehicle
+	) -> InstructResult {
+		let (state, remaining) = self.state(allocation);
+		light_instruct(allocation, vehicle, &self.signal_identity, state, remaining)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignKind {
+	// Every vehicle stops at the line before it looks for a gap.
+	Stop,
+	// Vehicles slow down and only stop when there is no gap.
+	Yield,
+}
+
+// Where the path past a sign crosses or joins a lane with priority.
+// Distance is along that lane.
+#[derive(Debug, Clone, Copy)]
+pub struct ConflictPoint {
+	pub lane: u32,
+	pub distance: f32,
+}
+
+// Stop or yield sign. A vehicle goes once no vehicle with priority reaches a
+// conflict point within the driver's critical gap after it reached the
+// line, and the follow up time after the vehicle before it went.
+#[derive(Debug, Clone)]
+pub struct PrioritySign {
+	pub signal_identity: SignalIdentity,
+	pub kind: SignKind,
+	pub conflicts: Vec<ConflictPoint>,
+	// Distance before a conflict point vehicles with priority are looked for.
+	pub range: f32,
+	// Speed vehicles approach a yield sign with.
+	pub approach_speed: f32,
+	// Below it a vehicle counts as stopped at a stop sign.
+	pub stopped_speed: f32,
+	// Distance before the line a vehicle has to stop within, with no vehicle
+	// between it and the line.
+	pub stop_zone: f32,
+	// Vehicles that stopped at the line.
+	stopped: HashSet<u32>,
+	// Time the last vehicle went.
+	last_release: Option<f64>,
+}
+
+impl PrioritySign {
+	pub fn new(
+		signal_identity: SignalIdentity,
+		kind: SignKind,
+		conflicts: Vec<ConflictPoint>,
+		range: f32,
+		approach_speed: f32,
+		stopped_speed: f32,
+		stop_zone: f32
+	) -> Self {
+		Self {
+			signal_identity,
+			kind,
+			conflicts,
+			range,
+			approach_speed,
+			stopped_speed,
+			stop_zone,
+			stopped: HashSet::new(),
+			last_release: None
+		}
+	}
+
+	// Whether the driver takes the gap, own being the seconds the vehicle
+	// needs to the line.
+	fn accepts(
+		&self,
+		allocation: &NetworkAllocation,
+		vehicle: &Vehicle,
+		own: f32
+	) -> bool {
+		let personality = &vehicle.driver_personality;
+		if let Some(last_release) = self.last_release {
+			if allocation.time() - last_release < personality.follow_up_time as f64 {
+				return false;
+			}
+		}
+		let sub = vehicle.data.identity.sub;
+		for conflict in self.conflicts.iter() {
+			let c_lane = allocation.lane(conflict.lane);
+			let ra_lane = c_lane.read().unwrap();
+			let blocked = ra_lane.vehicles.iter().any(
+				|x|
+				x.identity.sub != sub &&
+				x.distance >= conflict.distance &&
+				x.distance - x.length <= conflict.distance
+			);
+			drop(ra_lane);
+			if blocked {
+				return false;
+			}
+			let upstream = upstream_of(allocation, conflict.lane, conflict.distance, Some(sub), 0.0, self.range);
+			for x in upstream.iter() {
+				if x.gap / x.vehicle.speed.max(0.1) < own + personality.critical_gap {
+					return false;
+				}
+			}
+		}
+		true
+	}
+}
+
+#[async_trait]
+impl Signal for PrioritySign {
+	fn identity(&self) -> &SignalIdentity {
+		&self.signal_identity
+	}
+
+	fn identity_mut(&mut self) -> &mut SignalIdentity {
+		&mut self.signal_identity
+	}
+
+	fn activate(&mut self,
+		_allocation: &NetworkAllocation,
+		_vehicle: &Vehicle
+	) {}
+
+	fn instruct(&mut self,
+		allocation: &NetworkAllocation,
+		vehicle: &Vehicle
+	) -> InstructResult {
+		let sub = vehicle.data.identity.sub;
+		let distance = match stop_line_distance(allocation, vehicle, &self.signal_identity) {
+			Some(x) if x >= 0.0 => x,
+			_ => {
+				self.stopped.remove(&sub);
+				return InstructResult::DESTROY;
+			}
+		};
+		let personality = &vehicle.driver_personality;
+		let speed = vehicle.data.speed;
+
+		// APPROACH
+
+		match self.kind {
+			SignKind::Stop => {
+				if !self.stopped.contains(&sub) {
+					// A vehicle queued behind the one at the line has not
+					// stopped at the line yet.
+					let queued = vehicle.forward_vehicles.first().is_some_and(
+						|x|
+						x.distance < distance
+					);
+					if speed >= self.stopped_speed || distance > self.stop_zone || queued {
+						return stop_instruct(vehicle, distance);
+					}
+					self.stopped.insert(sub);
+				}
+			},
+			SignKind::Yield => {
+				let decision = speed * personality.reaction_time +
+					speed * speed / (2.0 * personality.willing_max_decel) +
+					self.stop_zone;
+				if distance > decision {
+					return InstructResult::SLOW(InstructSlow {
+						target_speed: self.approach_speed,
+						target: VTarget::DecTStop,
+						stop_distance: None
+					});
+				}
+			}
+		}
+
+		// GAP ACCEPTANCE
+
+		// Rolling on or starting from a stop, whichever is sooner.
+		let own = (distance / speed.max(0.01)).min((2.0 * distance / personality.willing_max_accel).sqrt());
+		if !self.accepts(allocation, vehicle, own) {
+			return stop_instruct(vehicle, distance);
+		}
+		self.stopped.remove(&sub);
+		self.last_release = Some(allocation.time());
+		InstructResult::DESTROY
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::network::{testing, vehicle::{Vehicle, VehicleData, VehicleIdentity, SpawnParams}, lane::LaneIdentity, Network};
+
+	use super::{PhasePlan, LightState, SignalIdentity, Signal, InstructResult, light_instruct, PrioritySign, SignKind, ConflictPoint};
+
+	#[test]
+	fn phase_plan_cycles() {
+		let plan = PhasePlan::default();
+		assert_eq!(plan.state(10.0), (LightState::Green, 20.0));
+		assert_eq!(plan.state(32.0), (LightState::Yellow, 2.0));
+		assert_eq!(plan.state(40.0), (LightState::Red, 20.0));
+		assert_eq!(plan.state(70.0), (LightState::Green, 20.0));
+		let shifted = PhasePlan {
+			offset: 10.0,
+			..Default::default()
+		};
+		assert_eq!(shifted.state(25.0), (LightState::Red, 25.0));
+		let empty = PhasePlan {
+			green: 0.0,
+			yellow: 0.0,
+			red: 0.0,
+			offset: 0.0
+		};
+		assert_eq!(empty.state(5.0), (LightState::Green, f32::INFINITY));
+	}
+
+	// Whether a vehicle at distance on lane 1 driving at speed is told to
+	// stop for a light 150 along the lane.
+	fn stops(distance: f32, speed: f32, state: LightState, remaining: f32) -> bool {
+		let (network, lanes) = testing::network();
+		let id = Vehicle::spawn(&network, lanes[0], lanes[6], SpawnParams {
+			distance,
+			speed,
+			..Default::default()
+		}).unwrap();
+		let identity = SignalIdentity {
+			signal_distance: 150.0,
+			lane: lanes[0].lane,
+			..Default::default()
+		};
+		testing::with_vehicle(&network, id.sub, |x| {
+			x.driver_personality.willing_max_decel = 4.0;
+			x.driver_personality.reaction_time = 1.0;
+			matches!(light_instruct(&network.allocation, x, &identity, state, remaining), InstructResult::SLOW(_))
+		}).unwrap()
+	}
+
+	#[test]
+	fn red_stops_vehicles_that_can_stop() {
+		assert!(stops(50.0, 10.0, LightState::Red, 20.0));
+		// Too close to stop, it crosses.
+		assert!(!stops(140.0, 15.0, LightState::Red, 20.0));
+		assert!(!stops(50.0, 10.0, LightState::Green, 20.0));
+	}
+
+	#[test]
+	fn yellow_goes_when_the_line_is_reached_before_red() {
+		assert!(!stops(50.0, 10.0, LightState::Yellow, 20.0));
+		assert!(stops(50.0, 10.0, LightState::Yellow, 2.0));
+		assert!(!stops(140.0, 15.0, LightState::Yellow, 0.5));
+	}
+
+	// Stop sign 150 along lane 1, its path crossing lane 7 at 100.
+	fn sign(lanes: &[LaneIdentity], kind: SignKind, range: f32) -> PrioritySign {
+		let identity = SignalIdentity {
+			signal_distance: 150.0,
+			lane: lanes[0].lane,
+			..Default::default()
+		};
+		let conflict = ConflictPoint {
+			lane: lanes[6].lane,
+			distance: 100.0
+		};
+		PrioritySign::new(identity, kind, vec![conflict], range, 8.0, 1.0, 20.0)
+	}
+
+	fn instruct(network: &Arc<Network>, sign: &mut PrioritySign, sub: u32) -> InstructResult {
+		let allocation = &network.allocation;
+		testing::with_vehicle(network, sub, |x| {
+			x.driver_personality.willing_max_decel = 4.0;
+			x.driver_personality.willing_max_accel = 2.0;
+			x.driver_personality.reaction_time = 1.0;
+			x.driver_personality.critical_gap = 5.0;
+			x.driver_personality.follow_up_time = 3.0;
+			x.pull_forward_lanes(allocation);
+			x.pull_forward_vehicles(allocation);
+			sign.instruct(allocation, x)
+		}).unwrap()
+	}
+
+	fn spawn(network: &Arc<Network>, lanes: &[LaneIdentity], distance: f32, speed: f32) -> u32 {
+		Vehicle::spawn(network, lanes[0], lanes[6], SpawnParams {
+			distance,
+			speed,
+			..Default::default()
+		}).unwrap().sub
+	}
+
+	#[test]
+	fn queued_vehicles_have_not_stopped_at_the_line() {
+		let (network, lanes) = testing::network();
+		let mut sign = sign(&lanes, SignKind::Stop, 200.0);
+		let first = spawn(&network, &lanes, 148.0, 0.0);
+		let second = spawn(&network, &lanes, 135.0, 0.0);
+		assert!(matches!(instruct(&network, &mut sign, second), InstructResult::SLOW(_)));
+		assert!(matches!(instruct(&network, &mut sign, first), InstructResult::DESTROY));
+		// Follow up time after the first went.
+		assert!(matches!(instruct(&network, &mut sign, second), InstructResult::SLOW(_)));
+	}
+
+	#[test]
+	fn stop_zone_and_stopped_speed_are_taken() {
+		let (network, lanes) = testing::network();
+		let sub = spawn(&network, &lanes, 140.0, 2.0);
+		let mut moving = sign(&lanes, SignKind::Stop, 200.0);
+		assert!(matches!(instruct(&network, &mut moving, sub), InstructResult::SLOW(_)));
+		let mut slow = sign(&lanes, SignKind::Stop, 200.0);
+		slow.stopped_speed = 3.0;
+		assert!(matches!(instruct(&network, &mut slow, sub), InstructResult::DESTROY));
+		// 10 before the line, outside the zone.
+		let mut short = sign(&lanes, SignKind::Stop, 200.0);
+		short.stopped_speed = 3.0;
+		short.stop_zone = 5.0;
+		assert!(matches!(instruct(&network, &mut short, sub), InstructResult::SLOW(_)));
+	}
+
+	#[test]
+	fn gaps_are_looked_for_within_range() {
+		let (network, lanes) = testing::network();
+		network.allocation.lane(lanes[6].lane).write().unwrap().insert_vehicle(VehicleData {
+			identity: VehicleIdentity {
+				sub: 1_000,
+				..Default::default()
+			},
+			length: 10.0,
+			distance: 70.0,
+			speed: 10.0,
+			..Default::default()
+		});
+		let sub = spawn(&network, &lanes, 149.0, 0.0);
+		// 3 seconds away, less than the critical gap.
+		let mut near = sign(&lanes, SignKind::Stop, 200.0);
+		assert!(matches!(instruct(&network, &mut near, sub), InstructResult::SLOW(_)));
+		let mut short = sign(&lanes, SignKind::Stop, 20.0);
+		assert!(matches!(instruct(&network, &mut short, sub), InstructResult::DESTROY));
+	}
+
+	#[test]
+	fn yield_approach_speed_is_taken() {
+		let (network, lanes) = testing::network();
+		let mut sign = sign(&lanes, SignKind::Yield, 200.0);
+		let sub = spawn(&network, &lanes, 20.0, 15.0);
+		match instruct(&network, &mut sign, sub) {
+			InstructResult::SLOW(x) => assert_eq!(x.target_speed, 8.0),
+			_ => panic!("not slowed")
+		}
+	}
+}
@@ -0,0 +1,43 @@
+// Fixtures for the unit tests.
+
+use std::sync::Arc;
+
+use super::{Network, lane::LaneIdentity, vehicle::Vehicle, event::NetworkEvent};
+
+// Network built by crate::setup and the identity of every lane in id order,
+// lanes[0] is lane 1.
+pub(crate) fn network() -> (Arc<Network>, Vec<LaneIdentity>) {
+	let network = Arc::new(Network::default());
+	crate::setup(&network);
+	let mut lanes: Vec<LaneIdentity> = network.allocation.lanes.read().unwrap().values().map(
+		|x|
+		x.read().unwrap().identity
+	).collect();
+	lanes.sort_by_key(
+		|x|
+		x.lane
+	);
+	(network, lanes)
+}
+
+// Runs f on the vehicle, None when it is gone.
+pub(crate) fn with_vehicle<R>(
+	network: &Arc<Network>,
+	sub: u32,
+	f: impl FnOnce(&mut Vehicle) -> R
+) -> Option<R> {
+	let vehicle_batch = network.allocation.vehicle_batch_of(sub)?;
+	let mut wa_vehicle_batch = vehicle_batch.write().unwrap();
+	wa_vehicle_batch.vehicle_mut(sub).map(f)
+}
+
+// Waypoints the vehicle reached so far, in order.
+pub(crate) fn reached(network: &Arc<Network>, sub: u32) -> Vec<usize> {
+	network.allocation.drain_events().iter().filter_map(
+		|x|
+		match x {
+			NetworkEvent::WaypointReached { vehicle, waypoint, .. } if vehicle.sub == sub => Some(*waypoint),
+			_ => None
+		}
+	).collect()
+}
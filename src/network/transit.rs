@@ -0,0 +1,408 @@
+use std::sync::Arc;
+
+use crate::network_allocation;
+
+use super::{lane::LaneIdentity, navigation::{Navigation, Waypoint}, vehicle::{Vehicle, SpawnParams}, vehicle_type::VehicleKind, Network, NetworkAllocation};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransitStop {
+	pub identity: LaneIdentity,
+	// Distance into the lane the vehicle stops at.
+	pub distance: f32,
+	// Seconds after the departure the vehicle is scheduled at the stop.
+	pub offset: f64,
+	// Passengers arriving at the stop per hour.
+	pub arrival_rate: f32,
+	// Share of the passengers on board leaving at the stop.
+	pub alighting_share: f32,
+}
+
+#[derive(Debug, Clone)]
+pub enum TransitSchedule {
+	// A departure every headway seconds, the first at start.
+	Headway {
+		headway: f64,
+		start: f64
+	},
+	// Departure times in simulation seconds, ascending.
+	Timetable(Vec<f64>),
+}
+
+impl TransitSchedule {
+	fn departure(&self, run: usize) -> Option<f64> {
+		match self {
+			TransitSchedule::Headway { headway, start } => Some(start + headway * run as f64),
+			TransitSchedule::Timetable(times) => times.get(run).copied()
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DwellModel {
+	// Seconds to open and close the doors.
+	pub fixed: f32,
+	pub per_boarding: f32,
+	pub per_alighting: f32,
+}
+
+impl Default for DwellModel {
+	fn default() -> Self {
+		Self {
+			fixed: 5.0,
+			per_boarding: 2.5,
+			per_alighting: 1.5
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransitRun {
+	// Index of the departure in the schedule.
+	pub run: usize,
+	pub vehicle: u32,
+	pub departure: f64,
+	// Passengers on board.
+	pub load: u32,
+	// Stop the vehicle heads to.
+	pub next_stop: usize,
+}
+
+// A vehicle serving a stop.
+#[derive(Debug, Clone, Copy)]
+pub struct StopRecord {
+	pub run: usize,
+	pub stop: usize,
+	pub vehicle: u32,
+	pub scheduled: f64,
+	pub arrival: f64,
+	pub boarding: u32,
+	pub alighting: u32,
+	pub dwell: f32,
+	// Seconds since the line's previous vehicle served the stop, None for
+	// the first one.
+	pub headway: Option<f64>,
+	// Headway the schedule planned between the two runs.
+	pub scheduled_headway: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LineReport {
+	pub arrivals: u32,
+	// Arrival minus scheduled arrival in seconds, positive when late.
+	pub mean_deviation: f64,
+	pub max_deviation: f64,
+	// Share of arrivals at most on_time seconds early or late.
+	pub on_time_share: f64,
+	// Arrivals that came less than half the scheduled headway after the
+	// previous vehicle.
+	pub bunched: u32,
+	// Standard deviation over mean of the headways at the stops.
+	pub headway_variation: f64,
+}
+
+// A line of vehicles serving stops along a route. Tick it every step after
+// the vehicles ticked, it dispatches the scheduled vehicles and lets them
+// dwell at the stops depending on how many passengers board and alight.
+#[derive(Debug)]
+pub struct TransitLine {
+	pub origin: LaneIdentity,
+	pub destination: LaneIdentity,
+	// Bands from the origin to the destination band. Empty routes every leg
+	// between two stops by itself.
+	pub bands: Vec<u32>,
+	pub stops: Vec<TransitStop>,
+	pub schedule: TransitSchedule,
+	pub dwell: DwellModel,
+	pub vehicle_kind: VehicleKind,
+	// Passengers a vehicle takes.
+	pub capacity: u32,
+	pub runs: Vec<TransitRun>,
+	pub records: Vec<StopRecord>,
+	// Passengers waiting at each stop, partial ones included.
+	pub waiting: Vec<f32>,
+	dispatched: usize,
+	// Arrival and run of the last vehicle at each stop.
+	last_arrivals: Vec<Option<(f64, usize)>>,
+}
+
+impl TransitLine {
+	pub fn new(
+		origin: LaneIdentity,
+		destination: LaneIdentity,
+		stops: Vec<TransitStop>,
+		schedule: TransitSchedule
+	) -> Self {
+		let count = stops.len();
+		Self {
+			origin,
+			destination,
+			bands: Vec::new(),
+			stops,
+			schedule,
+			dwell: DwellModel::default(),
+			vehicle_kind: VehicleKind::Bus,
+			capacity: 80,
+			runs: Vec::new(),
+			records: Vec::new(),
+			waiting: vec![0.0; count],
+			dispatched: 0,
+			last_arrivals: vec![None; count]
+		}
+	}
+
+	pub fn tick(
+		&mut self,
+		network: &Arc<Network>,
+		delta_time: f32
+	) {
+		let network_c = network.clone();
+		let allocation = network_allocation!(network_c);
+		let time = allocation.time();
+
+		// PASSENGERS
+
+		for (waiting, stop) in self.waiting.iter_mut().zip(self.stops.iter()) {
+			*waiting += stop.arrival_rate / 3_600.0 * delta_time;
+		}
+
+		// DISPATCH
+
+		while let Some(departure) = self.schedule.departure(self.dispatched) {
+			if departure > time {
+				break;
+			}
+			let vehicle = self.dispatch(network, allocation);
+			self.runs.push(TransitRun {
+				run: self.dispatched,
+				vehicle,
+				departure,
+				load: 0,
+				next_stop: 0
+			});
+			self.dispatched += 1;
+		}
+
+		// STOPS
+
+		let mut runs = std::mem::take(&mut self.runs);
+		runs.retain_mut(
+			|x|
+			self.serve(allocation, x, time)
+		);
+		self.runs = runs;
+	}
+
+	fn dispatch(
+		&self,
+		network: &Arc<Network>,
+		allocation: &NetworkAllocation
+	) -> u32 {
+		// The vehicle stands at a stop until serve sets the dwell from the
+		// passengers, in the step it arrives.
+		let mut waypoints: Vec<Waypoint> = self.stops.iter().map(
+			|x|
+			Waypoint {
+				identity: x.identity,
+				distance: x.distance,
+				dwell: f32::INFINITY
+			}
+		).collect();
+		let length = allocation.lane(self.destination.lane).read().unwrap().length;
+		waypoints.push(Waypoint {
+			identity: self.destination,
+			distance: length,
+			dwell: 0.0
+		});
+		let navigation = Navigation {
+			target_identity: waypoints[0].identity,
+			waypoints,
+			fixed_path: self.bands.clone(),
+			..Default::default()
+		};
+		Vehicle::with_navigation(
+			network,
+			self.origin,
+			navigation,
+			SpawnParams {
+				vehicle_kind: self.vehicle_kind,
+				..Default::default()
+			}
+		).sub
+	}
+
+	// Lets passengers on and off once the vehicle stands at its next stop.
+	// Returns false when the vehicle left the network.
+	fn serve(
+		&mut self,
+		allocation: &NetworkAllocation,
+		run: &mut TransitRun,
+		time: f64
+	) -> bool {
+		let vehicle_batch = match allocation.vehicle_batch_of(run.vehicle) {
+			Some(x) => x,
+			None => { return false; }
+		};
+		let mut wa_vehicle_batch = vehicle_batch.write().unwrap();
+		let vehicle = match wa_vehicle_batch.vehicle_mut(run.vehicle) {
+			Some(x) => x,
+			None => { return false; }
+		};
+		if run.next_stop >= self.stops.len() ||
+			vehicle.navigation.active_waypoint != run.next_stop ||
+			vehicle.dwell_remaining() <= 0.0 {
+			return true;
+		}
+
+		// BOARDING
+
+		let idx = run.next_stop;
+		let stop = self.stops[idx];
+		let alighting = (run.load as f32 * stop.alighting_share).round() as u32;
+		run.load -= alighting;
+		let boarding = (self.waiting[idx].floor() as u32).min(self.capacity - run.load);
+		run.load += boarding;
+		self.waiting[idx] -= boarding as f32;
+		let dwell = self.dwell.fixed +
+			boarding as f32 * self.dwell.per_boarding +
+			alighting as f32 * self.dwell.per_alighting;
+		vehicle.set_dwell(dwell);
+
+		// RECORD
+
+		let last = self.last_arrivals[idx];
+		self.records.push(StopRecord {
+			run: run.run,
+			stop: idx,
+			vehicle: run.vehicle,
+			scheduled: run.departure + stop.offset,
+			arrival: time,
+			boarding,
+			alighting,
+			dwell,
+			headway: last.map(|x| time - x.0),
+			scheduled_headway: last.and_then(
+				|x|
+				Some(run.departure - self.schedule.departure(x.1)?)
+			)
+		});
+		self.last_arrivals[idx] = Some((time, run.run));
+		run.next_stop += 1;
+		true
+	}
+
+	// Schedule adherence and bunching over all records. on_time is the
+	// deviation in seconds still counted as on time.
+	pub fn report(&self, on_time: f64) -> LineReport {
+		let mut report = LineReport::default();
+		if self.records.is_empty() {
+			return report;
+		}
+		let mut deviation_sum: f64 = 0.0;
+		let mut on_time_count: u32 = 0;
+		let mut headways: Vec<f64> = Vec::new();
+		for record in self.records.iter() {
+			let deviation = record.arrival - record.scheduled;
+			deviation_sum += deviation;
+			if report.arrivals == 0 || deviation.abs() > report.max_deviation.abs() {
+				report.max_deviation = deviation;
+			}
+			if deviation.abs() <= on_time {
+				on_time_count += 1;
+			}
+			report.arrivals += 1;
+			if let (Some(headway), Some(scheduled_headway)) = (record.headway, record.scheduled_headway) {
+				headways.push(headway);
+				if headway < 0.5 * scheduled_headway {
+					report.bunched += 1;
+				}
+			}
+		}
+		report.mean_deviation = deviation_sum / report.arrivals as f64;
+		report.on_time_share = on_time_count as f64 / report.arrivals as f64;
+		if !headways.is_empty() {
+			let mean = headways.iter().sum::<f64>() / headways.len() as f64;
+			let variance = headways.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / headways.len() as f64;
+			if mean > 0.0 {
+				report.headway_variation = variance.sqrt() / mean;
+			}
+		}
+		report
+	}
+
+	// Ticks every line in NetworkAllocation::transit_lines.
+	pub fn tick_all(
+		network: &Arc<Network>,
+		delta_time: f32
+	) {
+		let network_c = network.clone();
+		let allocation = network_allocation!(network_c);
+		let mut wa_lines = allocation.transit_lines.write().unwrap();
+		for line in wa_lines.iter_mut() {
+			line.tick(network, delta_time);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, Network};
+
+	use super::{TransitLine, TransitStop, TransitSchedule, DwellModel};
+
+	#[test]
+	fn dwell_is_set_from_the_passengers_on_arrival() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let stop = TransitStop {
+			identity: lanes[2],
+			distance: 100.0,
+			offset: 0.0,
+			arrival_rate: 3_600.0,
+			alighting_share: 0.0
+		};
+		let mut line = TransitLine::new(lanes[0], lanes[6], vec![stop], TransitSchedule::Timetable(vec![0.0]));
+		// Without a fixed part the vehicle still stops for its passengers.
+		line.dwell = DwellModel {
+			fixed: 0.0,
+			per_boarding: 2.0,
+			per_alighting: 0.0
+		};
+		let mut waiting_before: f32 = 0.0;
+		for _ in 0..2_000 {
+			waiting_before = line.waiting[0];
+			Network::tick(&network, 0.05);
+			line.tick(&network, 0.05);
+			if !line.records.is_empty() {
+				break;
+			}
+		}
+		let record = line.records[0];
+		assert_eq!(record.boarding, (waiting_before + 0.05).floor() as u32);
+		assert_eq!(record.dwell, record.boarding as f32 * 2.0);
+		let vehicle = record.vehicle;
+		let (distance, dwell) = testing::with_vehicle(&network, vehicle, |x| (x.data.distance, x.dwell_remaining())).unwrap();
+		assert!((distance - 100.0).abs() < 1.0);
+		assert_eq!(dwell, record.dwell);
+
+		// DESTINATION
+
+		allocation.drain_events();
+		let length = allocation.lane(lanes[6].lane).read().unwrap().length;
+		let mut last: Option<(u32, f32)> = None;
+		let mut reached = false;
+		for _ in 0..4_000 {
+			Network::tick(&network, 0.05);
+			line.tick(&network, 0.05);
+			if testing::reached(&network, vehicle).contains(&1) {
+				reached = true;
+				break;
+			}
+			last = testing::with_vehicle(&network, vehicle, |x| (x.active_identity.lane, x.data.distance));
+		}
+		// Reached at the end of the lane, not on entering it.
+		assert!(reached);
+		let (lane, distance) = last.unwrap();
+		assert_eq!(lane, lanes[6].lane);
+		assert!(distance > length - 20.0);
+	}
+}
@@ -1,10 +1,27 @@
-use std::{collections::VecDeque, sync::{atomic::Ordering, Arc}};
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::Ordering, Arc}};
 
+use rand::Rng;
 
 
-use crate::network::{network_allocation_mut, signal::InstructResult};
+use crate::{network::{network_allocation_mut, signal::InstructResult}, network_allocation};
 
-use super::{navigation::{Navigation, ForwardLane}, Network, lane::LaneIdentity, NetworkAllocation, NetworkVertex, BATCH_COUNT, signal::{Signal, InstructSlow}};
+use super::{navigation::{Navigation, ForwardLane, Waypoint}, event::NetworkEvent, following::{FollowingModel, FollowingInput}, lane_change::{LaneChange, choose_lane}, merge::{merge_leader, record_merge}, vehicle_type::{VehicleKind, VehicleType}, Network, lane::{LaneIdentity, leader_of, follower_of}, NetworkAllocation, NetworkVertex, BATCH_COUNT, LANE_SPEED, signal::{Signal, InstructSlow}};
+
+// Distance short of a waypoint at which it counts as reached.
+pub const WAYPOINT_TOLERANCE: f32 = 2.0;
+
+// Speed difference either side of the target speed in which the vehicle
+// coasts with no pedal pressed.
+const MAINTAIN_BAND: f32 = 1.0;
+// Pedal position per unit of speed difference the driver aims for.
+const PEDAL_GAIN: f32 = 0.05;
+// Distance of the pedal from its aim before the driver moves it again.
+const PEDAL_HOLD: f32 = 0.1;
+// Pedal travel per second.
+const GAS_RATE: f32 = 1.0;
+const BREAK_RATE: f32 = 2.0;
+// Break position that neither idles forward nor slows the vehicle.
+const BREAK_NEUTRAL: f32 = 0.1;
 
 pub enum TickStatus {
 	PERSIST,
@@ -23,20 +40,63 @@ pub struct VehicleIdentity {
 #[derive(Default, Debug)]
 pub struct VehicleBatch {
 	pub id: u32,
-	pub vehicles: Vec<Vehicle>,
+	// Add and remove vehicles with push and remove, slots has to follow.
+	vehicles: Vec<Vehicle>,
+	// Position in vehicles of each vehicle id.
+	slots: HashMap<u32, usize>,
 }
 
 impl VehicleBatch {
 	pub fn new(id: u32) -> Self {
 		let vb = VehicleBatch {
 			id,
-			vehicles: Vec::with_capacity(BATCH_COUNT)
+			vehicles: Vec::with_capacity(BATCH_COUNT),
+			slots: HashMap::with_capacity(BATCH_COUNT)
 		};
 		vb
 	}
 
+	pub fn push(&mut self, vehicle: Vehicle) {
+		self.slots.insert(vehicle.data.identity.sub, self.vehicles.len());
+		self.vehicles.push(vehicle);
+	}
+
+	// Moves the last vehicle into the removed vehicle's slot.
+	pub fn remove(&mut self, id: u32) -> Option<Vehicle> {
+		let slot = self.slots.remove(&id)?;
+		let vehicle = self.vehicles.swap_remove(slot);
+		if let Some(moved) = self.vehicles.get(slot) {
+			self.slots.insert(moved.data.identity.sub, slot);
+		}
+		Some(vehicle)
+	}
+
 	pub fn vehicle(&self, id: u32) -> Option<&Vehicle> {
-		self.vehicles.iter().find(|x| x.data.identity.sub == id)
+		self.vehicles.get(*self.slots.get(&id)?)
+	}
+
+	pub fn vehicle_mut(&mut self, id: u32) -> Option<&mut Vehicle> {
+		self.vehicles.get_mut(*self.slots.get(&id)?)
+	}
+
+	pub fn last(&self) -> Option<&Vehicle> {
+		self.vehicles.last()
+	}
+
+	pub fn iter(&self) -> std::slice::Iter<'_, Vehicle> {
+		self.vehicles.iter()
+	}
+
+	pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Vehicle> {
+		self.vehicles.iter_mut()
+	}
+
+	pub fn len(&self) -> usize {
+		self.vehicles.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.vehicles.is_empty()
 	}
 }
 
@@ -51,6 +111,9 @@ pub struct Vehicle {
 	pub navigation: Navigation,
 	pub active_identity: LaneIdentity,
 	pub driver_personality: DriverPersonality,
+	pub following_model: FollowingModel,
+	pub vehicle_type: VehicleType,
+	pub lane_change: Option<LaneChange>,
 
 	pub active_signals: Vec<Arc<dyn Signal>>,
 	pub forward_signals: Vec<Arc<dyn Signal>>,
@@ -62,13 +125,41 @@ pub struct Vehicle {
 	destroyed_active_signals: Vec<Arc<dyn Signal>>,
 	signal_instructs: Vec<InstructSlow>,
 	last_desired_delta: f32,
+	// Seconds spent on the active band and since the last reroute.
+	band_elapsed: f32,
+	reroute_elapsed: f32,
+	// Seconds until a blocked route is checked again.
+	blocked_retry: f32,
+	// Seconds left standing at the reached waypoint.
+	dwell_remaining: f32,
+	// Seconds left standing in an incident.
+	incident_remaining: f32,
+	// Seconds since the last lane change decision.
+	lane_change_elapsed: f32,
+	// What the driver saw, oldest first, by the vehicle's clock.
+	perceptions: VecDeque<Perception>,
+	clock: f32,
+}
+
+// Leader and signal state as seen by the driver at a point in time.
+#[derive(Debug, Clone, Copy)]
+struct Perception {
+	time: f32,
+	leader: Option<VehicleData>,
+	signal: InstructSlow,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct VehicleData {
 	pub identity: VehicleIdentity,
+	pub vehicle_type: VehicleKind,
+	pub length: f32,
+	pub width: f32,
 	pub speed: f32,
 	pub distance: f32,
+	// Offset from the lane's centre in lanes, positive towards higher
+	// lanes_fixed indices. Only set while changing lanes.
+	pub lateral: f32,
 	pub pdl_gas: f32,
 	pub pdl_break: f32,
 	pub target: VTarget,
@@ -111,10 +202,64 @@ pub enum VStage {
 	DecPull,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Copy)]
 pub struct DriverPersonality {
 	pub willing_max_accel: f32,
 	pub willing_max_decel: f32,
+	// Desired seconds to the leader.
+	pub time_headway: f32,
+	// Desired speed as a multiple of the lane speed.
+	pub speed_compliance: f32,
+	// Seconds before the driver reacts.
+	pub reaction_time: f32,
+	// Between 0 and 1.
+	pub aggressiveness: f32,
+	// Seconds to the next vehicle with priority the driver needs to enter
+	// past a stop or yield sign.
+	pub critical_gap: f32,
+	// Seconds the driver waits after the vehicle ahead went past the sign.
+	pub follow_up_time: f32,
+}
+
+impl Default for DriverPersonality {
+	fn default() -> Self {
+		Self {
+			willing_max_accel: 20.0,
+			willing_max_decel: 50.0,
+			time_headway: 1.0,
+			speed_compliance: 1.0,
+			reaction_time: 0.7,
+			aggressiveness: 0.0,
+			critical_gap: 6.5,
+			follow_up_time: 3.3
+		}
+	}
+}
+
+// Where and how a vehicle enters its source lane.
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnParams {
+	pub vehicle_kind: VehicleKind,
+	// Of the vehicle's front along the source lane.
+	pub distance: f32,
+	pub speed: f32,
+	pub target: VTarget,
+	pub stage: VStage,
+	// Free distance Vehicle::spawn needs to the vehicles ahead and behind.
+	pub clearance: f32,
+}
+
+impl Default for SpawnParams {
+	fn default() -> Self {
+		Self {
+			vehicle_kind: VehicleKind::Car,
+			distance: 0.0,
+			speed: 0.0,
+			target: VTarget::Wait,
+			stage: VStage::Wait,
+			clearance: 0.0
+		}
+	}
 }
 
 impl Vehicle {
@@ -123,6 +268,109 @@ impl Vehicle {
 		src_identity: LaneIdentity,
 		dst_identity: LaneIdentity
 	) -> VehicleIdentity {
+		Self::with_type(network, src_identity, dst_identity, VehicleKind::Car)
+	}
+
+	pub fn with_type(
+		network: &Arc<Network>,
+		src_identity: LaneIdentity,
+		dst_identity: LaneIdentity,
+		vehicle_kind: VehicleKind
+	) -> VehicleIdentity {
+		Self::allocate(
+			network,
+			src_identity,
+			Navigation {
+				target_identity: dst_identity,
+				..Default::default()
+			},
+			SpawnParams {
+				vehicle_kind,
+				..Default::default()
+			}
+		)
+	}
+
+	// Spawns a vehicle anywhere along the source lane, already moving if
+	// wanted. None when the vehicle would come closer than the clearance to a
+	// vehicle on the lane or the lanes before and after it.
+	pub fn spawn(
+		network: &Arc<Network>,
+		src_identity: LaneIdentity,
+		dst_identity: LaneIdentity,
+		params: SpawnParams
+	) -> Option<VehicleIdentity> {
+		let allocation = network_allocation!(network);
+		let lane_length = allocation.lane(src_identity.lane).read().unwrap().length;
+		if params.distance < 0.0 || params.distance >= lane_length {
+			return None;
+		}
+		let length = allocation.vehicle_types.read().unwrap().get(params.vehicle_kind).length;
+		let range = params.clearance + length;
+		let blocked_ahead = leader_of(allocation, src_identity.lane, params.distance, None, range).is_some_and(
+			|x|
+			x.distance < params.clearance
+		);
+		let blocked_behind = follower_of(allocation, src_identity.lane, params.distance, None, range).is_some_and(
+			|x|
+			x.distance - length < params.clearance
+		);
+		if blocked_ahead || blocked_behind {
+			return None;
+		}
+		Some(Self::allocate(
+			network,
+			src_identity,
+			Navigation {
+				target_identity: dst_identity,
+				..Default::default()
+			},
+			params
+		))
+	}
+
+	// Spawns a vehicle that visits the waypoints in order, routing one leg
+	// at a time. The last waypoint is the destination.
+	pub fn with_waypoints(
+		network: &Arc<Network>,
+		src_identity: LaneIdentity,
+		waypoints: Vec<Waypoint>,
+		vehicle_kind: VehicleKind
+	) -> VehicleIdentity {
+		let first = waypoints.first().expect("vehicle needs at least one waypoint").identity;
+		Self::allocate(
+			network,
+			src_identity,
+			Navigation {
+				target_identity: first,
+				waypoints,
+				..Default::default()
+			},
+			SpawnParams {
+				vehicle_kind,
+				..Default::default()
+			}
+		)
+	}
+
+	// Spawns a vehicle with a prepared navigation. With waypoints the target
+	// has to be the first one.
+	pub(crate) fn with_navigation(
+		network: &Arc<Network>,
+		src_identity: LaneIdentity,
+		navigation: Navigation,
+		params: SpawnParams
+	) -> VehicleIdentity {
+		Self::allocate(network, src_identity, navigation, params)
+	}
+
+	fn allocate(
+		network: &Arc<Network>,
+		src_identity: LaneIdentity,
+		navigation: Navigation,
+		params: SpawnParams
+	) -> VehicleIdentity {
+		let vehicle_kind = params.vehicle_kind;
 
 		let mut network_c = network.clone();
 		let mut allocation = network_allocation_mut!(network_c);
@@ -137,6 +385,13 @@ impl Vehicle {
 
 		// ALLOCATION
 
+		let informed = {
+			let informed_fraction = allocation.reroute_config.read().unwrap().informed_fraction;
+			allocation.rng.write().unwrap().0.gen::<f32>() < informed_fraction
+		};
+		let route_choice = navigation.route_choice.or(*allocation.route_choice.read().unwrap());
+		let vehicle_type = allocation.vehicle_types.read().unwrap().get(vehicle_kind);
+		let driver_personality = vehicle_type.personality.sample(allocation, &vehicle_type);
 		let mut vehicle = Self {
 			data: VehicleData {
 				identity: VehicleIdentity {
@@ -146,18 +401,28 @@ impl Vehicle {
 					band: src_identity.band,
 					clip: src_identity.clip
 				},
+				vehicle_type: vehicle_kind,
+				length: vehicle_type.length,
+				width: vehicle_type.width,
+				distance: params.distance,
+				speed: params.speed,
+				target: params.target,
+				stage: params.stage,
 				pdl_gas: 0.0,
-				pdl_break: 0.1,
+				pdl_break: match params.speed > 0.0 {
+					true => 0.0,
+					false => 0.1
+				},
 				..Default::default()
 			},
-			driver_personality: DriverPersonality {
-				willing_max_accel: 20.0,
-				willing_max_decel: 50.0,
-			},
+			driver_personality,
+			following_model: vehicle_type.following_model.clone(),
+			vehicle_type,
 			active_identity: src_identity,
 			navigation: Navigation {
-				target_identity: dst_identity,
-				..Default::default()
+				informed,
+				route_choice,
+				..navigation
 			},
 			..Default::default()
 		};
@@ -175,19 +440,17 @@ impl Vehicle {
 		let mut wa_svb = ra_svb_con.write().unwrap();
 		vehicle.data.identity.batch = wa_svb.id;
 		let vehicle_data = vehicle.data.clone();
-		wa_svb.vehicles.push(vehicle);
-		if /*wa_svb.vehicles.len() >= BATCH_COUNT*/ true {
+		wa_svb.push(vehicle);
+		allocation.vehicle_index.write().unwrap().insert(id, vehicle_data.identity.batch);
+		if wa_svb.len() >= BATCH_COUNT {
 			drop(wa_svb);
 			drop(ra_svb_con);
 			allocation.cycle_svb();
-
-
-			
 		}
 		
 		let c_lane = allocation.lane(src_identity.lane);
 		let mut wa_lane = c_lane.write().unwrap();
-		wa_lane.vehicles.push(vehicle_data);
+		wa_lane.insert_vehicle(vehicle_data);
 		drop(wa_lane);
 		drop(c_lane);
 		
@@ -212,20 +475,23 @@ impl Vehicle {
 		}
 	}
 
+	// Every vehicle ahead on the active and the forward lanes, closest
+	// first. Distances are to the vehicles' rears.
 	pub fn pull_forward_vehicles(
 		&mut self,
 		allocation: &NetworkAllocation
 	) {
 		self.forward_vehicles.clear();
+		let sub = self.data.identity.sub;
 		let active_lane = allocation.lane(self.active_identity.lane);
 		let ra_active_lane = active_lane.read().unwrap();
 		let mut accumulated_distance: f32 = ra_active_lane.length - self.data.distance;
-		for vehicle in ra_active_lane.vehicles.iter() {
-			if vehicle.distance < self.data.distance || vehicle.identity.sub == self.data.identity.sub {
+		for vehicle in ra_active_lane.ahead(self.data.distance) {
+			if vehicle.identity.sub == sub {
 				continue;
 			}
-			let mut vehicle_data = vehicle.clone();
-			vehicle_data.distance -= self.data.distance;
+			let mut vehicle_data = *vehicle;
+			vehicle_data.distance -= self.data.distance + vehicle.length;
 			self.forward_vehicles.push(vehicle_data);
 		}
 
@@ -235,14 +501,38 @@ impl Vehicle {
 			let c_lane = allocation.lane(lane.id);
 			let ra_lane = c_lane.read().unwrap();
 			for vehicle in ra_lane.vehicles.iter() {
-				let mut vehicle_data = vehicle.clone();
-				vehicle_data.distance += accumulated_distance;
+				if vehicle.identity.sub == sub {
+					continue;
+				}
+				let mut vehicle_data = *vehicle;
+				vehicle_data.distance += accumulated_distance - vehicle.length;
 				self.forward_vehicles.push(vehicle_data);
 			}
 			accumulated_distance += lane.length;
 		}
 	}
 
+	// Puts the vehicle to let go first at a merge into the forward vehicles
+	// by its distance.
+	pub fn pull_merge_vehicle(
+		&mut self,
+		allocation: &NetworkAllocation
+	) {
+		let merge_vehicle = match merge_leader(allocation, self) {
+			Some(x) => x,
+			None => { return; }
+		};
+		self.forward_vehicles.retain(
+			|x|
+			x.identity.sub != merge_vehicle.identity.sub
+		);
+		let idx = self.forward_vehicles.partition_point(
+			|x|
+			x.distance <= merge_vehicle.distance
+		);
+		self.forward_vehicles.insert(idx, merge_vehicle);
+	}
+
 	pub fn pull_forward_signals(
 		&mut self,
 		allocation: &NetworkAllocation
@@ -305,66 +595,53 @@ impl Vehicle {
 		delta_time: f32
 	) -> TickStatus {
 		
+		// INCIDENT
+
+		if self.incident_remaining > 0.0 {
+			self.incident_remaining -= delta_time;
+			return TickStatus::PERSIST;
+		}
+
+		// DWELL
+
+		if self.dwell_remaining > 0.0 {
+			self.dwell_remaining -= delta_time;
+			if self.dwell_remaining <= 0.0 {
+				self.dwell_remaining = 0.0;
+				self.navigation.next_leg(allocation, self.active_identity);
+			}
+			return TickStatus::PERSIST;
+		}
+
 		// println!("{:?}", self.navigation);
+		let start = self.data.distance;
 		self.data.distance += self.data.speed * delta_time;
 		// self.speed += self.acceleration * delta_time;
+		self.band_elapsed += delta_time;
+		self.reroute_elapsed += delta_time;
+
+		// REROUTE
+
+		if self.navigation.informed && self.should_reroute(allocation) {
+			self.reroute(allocation);
+		}
 
-		// TEMP: MOVE TO BEST LANE
+		// CLOSURES
+
+		// Checked every tick, a closure can start while the vehicle is in the
+		// middle of a lane. Without a way around it tries again after the
+		// cooldown.
+		self.blocked_retry -= delta_time;
+		if self.blocked_retry <= 0.0 && self.navigation.route_blocked(allocation, &self.active_identity) &&
+			!self.reroute(allocation) {
+			self.blocked_retry = allocation.reroute_config.read().unwrap().cooldown;
+		}
 
 		self.pull_forward_lanes(allocation);
 
-		// if /*self.forward_lanes.len() <= 1*/ false {  
-		// 	let c_clip = allocation.clip(self.active_identity.clip);
-		// 	let ra_clip = c_clip.read();
-		// 	let mut best_id: u32 = 0;
-		// 	let mut best_diff: i8 = i8::MAX;
-
-
-		// 	let fixed_current = ra_clip.lanes_fixed.iter().enumerate().find(
-		// 		|x|
-		// 		{
-		// 			let f = x.1;
-		// 			for i in 0..f.fw_count {
-		// 				if f.fw[i as usize] == self.active_identity.lane {
-		// 					return true;
-		// 				}
-		// 			}
-		// 			false
-		// 		}
-		// 	).expect("target lane does not exist");
-
-
-
-		// 	// let fixed_current = ra_clip.lanes_fixed.iter().enumerate().find(
-		// 	// 	|x|
-		// 	// 	x.1.fw == self.active_identity.lane
-		// 	// ).expect("vehicle active lane does not belong to active clip");
-		// 	for lane_id in self.navigation.nav_valid_band_lanes[self.navigation.active_nav as usize].iter() {
-		// 		if let Some(fixed_valid) = ra_clip.lanes_fixed.iter().enumerate().find(
-		// 			|x|
-		// 			{
-		// 				let f = x.1;
-		// 				for i in 0..f.fw_count {
-		// 					if f.fw[i as usize] == *lane_id {
-		// 						return true;
-		// 					}
-		// 				}
-		// 				false
-		// 			}
-		// 		) {
-		// 			let diff_abs: i8 = ((fixed_valid.0 as i8) - (fixed_current.0 as i8)).abs();
-		// 			if diff_abs < best_diff as i8 {
-		// 				best_diff = diff_abs;
-		// 				best_id = *lane_id;
-		// 			}
-		// 		}
-		// 	}
-
-		// 	if best_id > 0 && best_id != self.active_identity.lane {
-		// 		self.active_identity.lane = best_id;
-		// 		println!("moving to best lane");
-		// 	}
-		// }
+		// LANE CHANGE
+
+		self.update_lane_change(allocation, delta_time);
 
 		let mut lane: u32 = self.active_identity.lane;
 
@@ -379,26 +656,31 @@ impl Vehicle {
 
 		let c_lane = allocation.lane(lane);
 		let mut wa_lane = c_lane.write().unwrap();
-		let lane_speed: f32 = 100.0;
-		let mut vehicle_int = wa_lane.vehicles.iter_mut().find(
+		let lane_speed: f32 = LANE_SPEED;
+		self.stop_at_waypoint(lane, wa_lane.length, start);
+		let data = self.data;
+		let updated = wa_lane.update_vehicle(
+			data.identity.sub,
+			data.distance,
 			|x|
-			x.identity.sub == self.data.identity.sub
-		).expect("vehicle does not exist in lane");
-		vehicle_int.distance = self.data.distance;
-		vehicle_int.speed = self.data.speed;
+			{
+				x.distance = data.distance;
+				x.speed = data.speed;
+				x.lateral = data.lateral;
+			}
+		);
+		assert!(updated, "vehicle does not exist in lane");
 		if self.data.distance < wa_lane.length {
 			drop(wa_lane);
 			drop(c_lane);
 			return self.tick_st(allocation, delta_time, lane_speed);
 		}
 
-		if self.forward_lanes.is_empty() {
-			if let Some(idx) = wa_lane.vehicles.iter().position(
-				|x|
-				x.identity.sub == self.data.identity.sub
-			) {
-				wa_lane.vehicles.swap_remove(idx);
-			} else {
+		// The lane being left ends with the active lane.
+		self.finish_lane_change(allocation);
+
+		if self.forward_lanes.is_empty() || wa_lane.sink {
+			if wa_lane.remove_vehicle(self.data.identity.sub, self.data.distance).is_none() {
 				println!("failed to remove vehicle from lane; id does not exist in specified lane");
 			}
 			return TickStatus::DESTROY;
@@ -420,14 +702,12 @@ impl Vehicle {
 			let c_lane = allocation.lane(lane);
 			let mut wa_lane = c_lane.write().unwrap();
 
-			if let Some(idx) = wa_lane.vehicles.iter().position(
-				|x|
-				x.identity.sub == self.data.identity.sub
-			) {
-				wa_lane.vehicles.swap_remove(idx);
-			} else {
+			if wa_lane.remove_vehicle(self.data.identity.sub, self.data.distance).is_none() {
 				println!("failed to remove vehicle from lane; id does not exist in specified lane");
 			}
+			if wa_lane.sink {
+				return TickStatus::DESTROY;
+			}
 			self.data.distance -= wa_lane.length;
 			// if self.forward_lanes.is_empty() {
 			// 	// let a = &mut *network;
@@ -439,9 +719,10 @@ impl Vehicle {
 				None => {
 					// commands.entity(self.entity).despawn();
 					println!("despawning: no forward lanes ");
-					break;
+					return TickStatus::DESTROY;
 				}
 			};
+			record_merge(allocation, lane, fw_lane.id);
 			self.active_identity.lane = fw_lane.id;
 			self.data.identity.lane = fw_lane.id;
 			lane = fw_lane.id;
@@ -449,12 +730,17 @@ impl Vehicle {
 			drop(c_lane);
 			let c_lane = allocation.lane(lane);
 			let mut wa_lane = c_lane.write().unwrap();
+			if wa_lane.identity.band != self.active_identity.band {
+				allocation.band(self.active_identity.band).write().unwrap().record_travel_time(self.band_elapsed);
+				self.band_elapsed = 0.0;
+			}
 			self.active_identity.band = wa_lane.identity.band;
 			self.active_identity.clip = wa_lane.identity.clip;
 			self.data.identity.band = wa_lane.identity.band;
 			self.data.identity.clip = wa_lane.identity.clip;
 			self.forward_length -= fw_lane.length;
-			wa_lane.vehicles.push(self.data.clone());
+			self.stop_at_waypoint(lane, wa_lane.length, 0.0);
+			wa_lane.insert_vehicle(self.data);
 			// println!("inc active nav to {}", self.navigation.active_nav);
 
 			// let v_clip = self.active_identity.clip;
@@ -491,35 +777,328 @@ impl Vehicle {
 		// transform.translation = Vec3::new(v_pos.x, v_pos.y, 1.0);
 	}
 
+	fn update_lane_change(
+		&mut self,
+		allocation: &NetworkAllocation,
+		delta_time: f32
+	) {
+		let config = *allocation.lane_change_config.read().unwrap();
+		if let Some(lane_change) = self.lane_change.as_mut() {
+			lane_change.progress += delta_time / config.duration;
+			if lane_change.progress >= 1.0 {
+				self.finish_lane_change(allocation);
+				return;
+			}
+			let lane_change = *lane_change;
+			self.data.lateral = lane_change.side * (1.0 - lane_change.progress);
+
+			// MOVE ON LEFT LANE
+
+			let length = allocation.lane(self.active_identity.lane).read().unwrap().length;
+			let c_lane = allocation.lane(lane_change.from.lane);
+			let mut wa_lane = c_lane.write().unwrap();
+			let distance = self.data.distance / length * wa_lane.length;
+			let speed = self.data.speed;
+			wa_lane.update_vehicle(
+				self.data.identity.sub,
+				distance,
+				|x|
+				{
+					x.distance = distance;
+					x.speed = speed;
+					x.lateral = -lane_change.side * lane_change.progress;
+				}
+			);
+			return;
+		}
+		self.lane_change_elapsed += delta_time;
+		if self.lane_change_elapsed < config.interval || self.dwell_remaining > 0.0 {
+			return;
+		}
+		self.lane_change_elapsed = 0.0;
+		if let Some(target) = choose_lane(allocation, self, &config) {
+			self.begin_lane_change(allocation, target);
+			self.pull_forward_lanes(allocation);
+		}
+	}
+
+	// Moves the vehicle onto a lateral lane. It stays in the lane it left
+	// until the change completes.
+	fn begin_lane_change(
+		&mut self,
+		allocation: &NetworkAllocation,
+		target: LaneIdentity
+	) {
+		let from = self.active_identity;
+		let side = {
+			let c_clip = allocation.clip(from.clip);
+			let ra_clip = c_clip.read().unwrap();
+			match ra_clip.fw_index(from.lane) < ra_clip.fw_index(target.lane) {
+				true => -1.0,
+				false => 1.0
+			}
+		};
+		let from_length = allocation.lane(from.lane).read().unwrap().length;
+		let c_lane = allocation.lane(target.lane);
+		let mut wa_lane = c_lane.write().unwrap();
+		self.data.distance = self.data.distance / from_length * wa_lane.length;
+		self.data.lateral = side;
+		self.active_identity = target;
+		self.data.identity.lane = target.lane;
+		self.data.identity.band = target.band;
+		self.data.identity.clip = target.clip;
+		wa_lane.insert_vehicle(self.data);
+		self.lane_change = Some(LaneChange {
+			from,
+			progress: 0.0,
+			side
+		});
+	}
+
+	fn finish_lane_change(
+		&mut self,
+		allocation: &NetworkAllocation
+	) {
+		let lane_change = match self.lane_change.take() {
+			Some(x) => x,
+			None => { return; }
+		};
+		self.data.lateral = 0.0;
+		let c_lane = allocation.lane(lane_change.from.lane);
+		let mut wa_lane = c_lane.write().unwrap();
+		wa_lane.remove_vehicle(self.data.identity.sub, self.data.distance);
+	}
+
+	// Keeps the vehicle from driving past the active waypoint on the lane in
+	// one tick, so update_waypoint sees it even right before the lane end.
+	// start is the distance the vehicle had on the lane before the tick.
+	fn stop_at_waypoint(
+		&mut self,
+		lane: u32,
+		length: f32,
+		start: f32
+	) {
+		let waypoint = match self.navigation.waypoint() {
+			Some(x) if x.identity.lane == lane && start <= x.distance => *x,
+			_ => { return; }
+		};
+		let stop = waypoint.distance.min(length - WAYPOINT_TOLERANCE).max(start);
+		self.data.distance = self.data.distance.min(stop);
+	}
+
+	fn update_waypoint(
+		&mut self,
+		allocation: &NetworkAllocation
+	) {
+		let waypoint = match self.navigation.waypoint() {
+			Some(x) => *x,
+			None => { return; }
+		};
+		if waypoint.identity.lane != self.active_identity.lane ||
+			self.data.distance < waypoint.distance - WAYPOINT_TOLERANCE {
+			return;
+		}
+		allocation.push_event(NetworkEvent::WaypointReached {
+			vehicle: self.data.identity,
+			waypoint: self.navigation.active_waypoint,
+			identity: waypoint.identity
+		});
+		if waypoint.dwell <= 0.0 {
+			self.navigation.next_leg(allocation, self.active_identity);
+			return;
+		}
+
+		// STOP
+
+		self.dwell_remaining = waypoint.dwell;
+		self.data.speed = 0.0;
+		self.data.stage = VStage::Wait;
+		self.data.target = VTarget::Wait;
+		let c_lane = allocation.lane(self.active_identity.lane);
+		let mut wa_lane = c_lane.write().unwrap();
+		wa_lane.update_vehicle(
+			self.data.identity.sub,
+			self.data.distance,
+			|x|
+			x.speed = 0.0
+		);
+	}
+
+	// Seconds the vehicle still stands at the reached waypoint.
+	pub fn dwell_remaining(&self) -> f32 {
+		self.dwell_remaining
+	}
+
+	// Changes how long the vehicle stands at the reached waypoint. It leaves
+	// on its next tick at the earliest.
+	pub fn set_dwell(&mut self, seconds: f32) {
+		if self.dwell_remaining > 0.0 {
+			self.dwell_remaining = seconds.max(f32::EPSILON);
+		}
+	}
+
+	// Stops the vehicle where it is for seconds, used for incidents.
+	pub fn hold(
+		&mut self,
+		allocation: &NetworkAllocation,
+		seconds: f32
+	) {
+		self.incident_remaining = self.incident_remaining.max(seconds);
+		self.data.speed = 0.0;
+		self.data.stage = VStage::Wait;
+		self.data.target = VTarget::Wait;
+		let c_lane = allocation.lane(self.active_identity.lane);
+		let mut wa_lane = c_lane.write().unwrap();
+		wa_lane.update_vehicle(
+			self.data.identity.sub,
+			self.data.distance,
+			|x|
+			x.speed = 0.0
+		);
+	}
+
+	// Distance to the active waypoint if it is on the active or a forward lane.
+	pub fn waypoint_distance(
+		&self,
+		allocation: &NetworkAllocation
+	) -> Option<f32> {
+		let waypoint = self.navigation.waypoint()?;
+		if waypoint.identity.lane == self.active_identity.lane {
+			return Some(waypoint.distance - self.data.distance);
+		}
+		self.distance_from_fw(allocation, waypoint.distance, waypoint.identity.lane)
+	}
+
+	fn should_reroute(
+		&self,
+		allocation: &NetworkAllocation
+	) -> bool {
+		let config = *allocation.reroute_config.read().unwrap();
+		if self.reroute_elapsed >= config.period {
+			return true;
+		}
+		if self.reroute_elapsed < config.cooldown {
+			return false;
+		}
+		let free_flow_time = allocation.lane(self.active_identity.lane).read().unwrap().length / LANE_SPEED;
+		self.band_elapsed > free_flow_time * config.delay_factor
+	}
+
+	// Renavigates from the active lane. The previous route is kept when no
+	// route could be found.
+	pub fn reroute(
+		&mut self,
+		allocation: &NetworkAllocation
+	) -> bool {
+		self.reroute_elapsed = 0.0;
+		if self.active_identity.band == self.navigation.target_identity.band {
+			return false;
+		}
+		let active_nav = self.navigation.active_nav;
+		let nav = self.navigation.nav.clone();
+		let nav_valid_band_lanes = self.navigation.nav_valid_band_lanes.clone();
+		let lane_plan = self.navigation.lane_plan.clone();
+		if self.navigation.renavigate(allocation, self.active_identity) {
+			return true;
+		}
+		self.navigation.active_nav = active_nav;
+		self.navigation.nav = nav;
+		self.navigation.nav_valid_band_lanes = nav_valid_band_lanes;
+		self.navigation.lane_plan = lane_plan;
+		false
+	}
+
 	fn tick_st(
 		&mut self,
 		allocation: &NetworkAllocation,
 		delta_time: f32,
 		lane_speed: f32
 	) -> TickStatus {
+		let lane_speed = (lane_speed * self.driver_personality.speed_compliance).min(self.vehicle_type.max_speed);
+		self.update_waypoint(allocation);
+		if self.dwell_remaining > 0.0 {
+			return TickStatus::PERSIST;
+		}
 		self.pull_forward_vehicles(allocation);
+		self.pull_merge_vehicle(allocation);
 		self.pull_forward_signals(allocation);
-		if self.forward_vehicles.is_empty() {
-			println!("fwv dis: NONE");
-			self.update_target_solo(lane_speed);
-			let signal_instruct = self.calc_signal_target(allocation, lane_speed);
-			if signal_instruct.target_speed < lane_speed {
-				self.data.target = signal_instruct.target;
-				self.update_stage(delta_time, signal_instruct.target_speed);
-			} else {
+		self.clock += delta_time;
+		let perception = Perception {
+			time: self.clock,
+			leader: self.forward_vehicles.first().copied(),
+			signal: self.calc_signal_target(allocation, lane_speed)
+		};
+		let following_model = self.following_model.clone();
+		let delay = match following_model.model().includes_reaction() {
+			true => 0.0,
+			false => self.driver_personality.reaction_time
+		};
+		let perceived = self.perceive(perception, delay);
+		// A stop line closer than the leader stands in for it.
+		let leader = match perceived.signal.stop_distance {
+			Some(distance) if perceived.leader.is_none_or(|x| distance < x.distance) => Some(VehicleData {
+				distance,
+				speed: 0.0,
+				length: 0.0,
+				..Default::default()
+			}),
+			_ => perceived.leader
+		};
+		let input = FollowingInput {
+			leader,
+			lane_speed,
+			signal: perceived.signal,
+			delta_time
+		};
+		let acceleration = following_model.model().acceleration(allocation, self, &input);
+		self.data.speed += delta_time * acceleration;
+		if self.data.speed <= 0.0 {
+			self.data.speed = 0.0;
+		}
+
+		TickStatus::PERSIST
+	}
+
+	// The newest perception at least delay seconds old. Until the buffer is
+	// that long the oldest one is used.
+	fn perceive(
+		&mut self,
+		perception: Perception,
+		delay: f32
+	) -> Perception {
+		self.perceptions.push_back(perception);
+		let seen = perception.time - delay;
+		while self.perceptions.len() > 1 && self.perceptions[1].time <= seen {
+			self.perceptions.pop_front();
+		}
+		self.perceptions[0]
+	}
+
+	// Target speed of the pedal model from VTarget, driven through VStage.
+	pub(crate) fn pedal_acceleration(
+		&mut self,
+		input: &FollowingInput
+	) -> f32 {
+		let delta_time = input.delta_time;
+		let lane_speed = input.lane_speed;
+		let signal_instruct = input.signal;
+		let fw_vehicle = match input.leader {
+			Some(x) => x,
+			None => {
+				println!("fwv dis: NONE");
+				self.update_target_solo(lane_speed);
+				if signal_instruct.target_speed < lane_speed {
+					self.data.target = signal_instruct.target;
+					return self.update_stage(delta_time, signal_instruct.target_speed);
+				}
 				println!("%%%%%%%%%%%\n%%%%%%%%%%%\n%%%%%%%%%");
-				self.update_stage(delta_time, lane_speed);
+				return self.update_stage(delta_time, lane_speed);
 			}
-			return TickStatus::PERSIST;
-		}
-		self.update_target_fw();
-		let signal_instruct = self.calc_signal_target(allocation, lane_speed);
-		let fw_vehicle = self.forward_vehicles.first().unwrap();
-		let vb = allocation.vehicle_batch(fw_vehicle.identity.batch);
-		let ra_vb = vb.read().unwrap();
+		};
+		self.update_target_fw(&fw_vehicle);
 		let seconds_to_vehicle = self.data.seconds_to_moving(fw_vehicle.distance, fw_vehicle.speed);
 		match self.data.target {
-			VTarget::Wait => {},
+			VTarget::Wait => 0.0,
 			// VTarget::AccFStop => {
 			// 	let focus
 			// 	self.update_stage(delta_time, lane_speed.min(fw_vehicle.speed));
@@ -530,21 +1109,19 @@ impl Vehicle {
 				if min_speed == signal_instruct.target_speed {
 					self.data.target = signal_instruct.target;
 				}
-				self.update_stage(delta_time, min_speed);
+				self.update_stage(delta_time, min_speed)
 			},
 			VTarget::AvgSpeed | VTarget::AccFStop => {
 				let focus_out = ((seconds_to_vehicle - 2.0) * 0.25).clamp(0.0, 1.0);
 				let target_speed = (fw_vehicle.speed * (1.0 - focus_out)) + (lane_speed * focus_out);
 				if signal_instruct.target_speed < target_speed {
 					self.data.target = signal_instruct.target;
-					self.update_stage(delta_time, signal_instruct.target_speed);
+					self.update_stage(delta_time, signal_instruct.target_speed)
 				} else {
-					self.update_stage(delta_time, target_speed);
+					self.update_stage(delta_time, target_speed)
 				}
 			}
-		};
-
-		TickStatus::PERSIST
+		}
 	}
 
 	#[allow(unreachable_code)]
@@ -570,14 +1147,14 @@ impl Vehicle {
 
 	pub fn update_target_fw(
 		&mut self,
+		fw_vehicle: &VehicleData
 	) {
-		let fw_vehicle = self.forward_vehicles.first().unwrap();
 		match self.data.target {
 			VTarget::Wait => {
 				if fw_vehicle.distance > 10.0 ||
 					(fw_vehicle.speed - self.data.speed) > 5.0 {
 					self.data.target = VTarget::AccFStop;
-					self.update_target_fw();
+					self.update_target_fw(fw_vehicle);
 					return;
 				}
 			},
@@ -589,12 +1166,12 @@ impl Vehicle {
 					match fw_vehicle.target {
 						VTarget::DecTStop => {
 							self.data.target = VTarget::DecTStop;
-							self.update_target_fw();
+							self.update_target_fw(fw_vehicle);
 							return;
 						},
 						_ => {
 							self.data.target = VTarget::AvgSpeed;
-							self.update_target_fw();
+							self.update_target_fw(fw_vehicle);
 							return;
 						}
 					}
@@ -603,7 +1180,7 @@ impl Vehicle {
 				if fw_vehicle.distance < 30.0 &&
 					(fw_vehicle.speed - self.data.speed) < 1.0 {
 					self.data.target = VTarget::AvgSpeed;
-					self.update_target_fw();
+					self.update_target_fw(fw_vehicle);
 					return;
 				}
 			},
@@ -615,7 +1192,7 @@ impl Vehicle {
 			VTarget::AvgSpeed => {
 				if (fw_vehicle.speed - self.data.speed) > 10.0 {
 					self.data.target = VTarget::AccFStop;
-					self.update_target_fw();
+					self.update_target_fw(fw_vehicle);
 					return;
 				}
 				// CLONE; TODO: OUT SOURCE TO FUNCTION
@@ -624,7 +1201,7 @@ impl Vehicle {
 					match fw_vehicle.target {
 						VTarget::DecTStop => {
 							self.data.target = VTarget::DecTStop;
-							self.update_target_fw();
+							self.update_target_fw(fw_vehicle);
 							return;
 						},
 						_ => {}
@@ -639,7 +1216,7 @@ impl Vehicle {
 		&mut self,
 		delta_time: f32,
 		target_speed: f32
-	) {
+	) -> f32 {
 		let tolerance: f32 = 0.01;
 		let desired_delta = target_speed - self.data.speed;
 		let delta_delta = desired_delta - self.last_desired_delta;
@@ -651,19 +1228,17 @@ impl Vehicle {
 			VStage::Wait => {
 				if desired_delta > tolerance {
 					self.data.stage = VStage::LiftPush;
-					self.update_stage(delta_time, target_speed);
-					return;
+					return self.update_stage(delta_time, target_speed);
 				}
 			},
 			VStage::LiftPush => {
 				if desired_delta >= 0.0 && desired_delta < tolerance {
 					self.data.stage = VStage::LiftHold;
-					return;
+					return 0.0;
 				}
 				if desired_delta < 0.0 {
 					self.data.stage = VStage::LiftPull;
-					self.update_stage(delta_time, target_speed);
-					return;
+					return self.update_stage(delta_time, target_speed);
 				}
 				// RELEASING BREAK
 				self.data.pdl_break -= delta_time * (0.05 * desired_delta).clamp(0.0, 1.0);
@@ -674,26 +1249,23 @@ impl Vehicle {
 			},
 			VStage::LiftHold => {
 				if desired_delta >= 0.0 && desired_delta < tolerance {
-					return;
+					return 0.0;
 				}
 				if delta_delta * delta_time > 0.5 && delta_delta * delta_time < 5.0 && desired_delta.abs() < 10.0 {
-					return;
+					return 0.0;
 				}
 				if desired_delta < 0.0 {
 					self.data.stage = VStage::LiftPull;
-					self.update_stage(delta_time, target_speed);
-					return;
+					return self.update_stage(delta_time, target_speed);
 				}
 				// desired_delta > tolerance
 				self.data.stage = VStage::LiftPush;
-				self.update_stage(delta_time, target_speed);
-				return;
+				return self.update_stage(delta_time, target_speed);
 			},
 			VStage::LiftPull => {
 				if desired_delta >= 0.0 || delta_delta * delta_time > 0.5 {
 					self.data.stage = VStage::LiftHold;
-					self.update_stage(delta_time, target_speed);
-					return;
+					return self.update_stage(delta_time, target_speed);
 				}
 				// PRESSING BREAK
 				if delta_delta * delta_time <= 0.0 {
@@ -706,35 +1278,127 @@ impl Vehicle {
 			VStage::AccWait => {
 				if desired_delta < 0.0 {
 					self.data.stage = VStage::LiftPull;
-					self.update_stage(delta_time, target_speed);
-					return;
+					return self.update_stage(delta_time, target_speed);
+				}
+				if desired_delta > MAINTAIN_BAND {
+					self.data.stage = VStage::AccPush;
+					return self.update_stage(delta_time, target_speed);
 				}
 			},
 			VStage::AccPush => {
-
+				if desired_delta < -MAINTAIN_BAND {
+					self.data.stage = VStage::DecPush;
+					return self.update_stage(delta_time, target_speed);
+				}
+				if desired_delta <= MAINTAIN_BAND {
+					self.data.stage = VStage::AccPull;
+					return self.update_stage(delta_time, target_speed);
+				}
+				// PRESSING GAS
+				let gas_target = (desired_delta * PEDAL_GAIN).clamp(0.0, 1.0);
+				if self.data.pdl_gas >= gas_target {
+					self.data.stage = VStage::AccHold;
+					return self.update_stage(delta_time, target_speed);
+				}
+				self.data.pdl_gas = (self.data.pdl_gas + delta_time * GAS_RATE).min(gas_target);
 			},
 			VStage::AccHold => {
-
+				if desired_delta < -MAINTAIN_BAND {
+					self.data.stage = VStage::DecPush;
+					return self.update_stage(delta_time, target_speed);
+				}
+				let gas_target = (desired_delta * PEDAL_GAIN).clamp(0.0, 1.0);
+				if desired_delta <= MAINTAIN_BAND || self.data.pdl_gas > gas_target + PEDAL_HOLD {
+					self.data.stage = VStage::AccPull;
+					return self.update_stage(delta_time, target_speed);
+				}
+				if self.data.pdl_gas < gas_target - PEDAL_HOLD {
+					self.data.stage = VStage::AccPush;
+					return self.update_stage(delta_time, target_speed);
+				}
 			},
 			VStage::AccPull => {
-
+				if desired_delta < -MAINTAIN_BAND {
+					self.data.stage = VStage::DecPush;
+					return self.update_stage(delta_time, target_speed);
+				}
+				// RELEASING GAS
+				let gas_target = match desired_delta > MAINTAIN_BAND {
+					true => (desired_delta * PEDAL_GAIN).clamp(0.0, 1.0),
+					false => 0.0
+				};
+				self.data.pdl_gas = (self.data.pdl_gas - delta_time * GAS_RATE).max(gas_target);
+				if self.data.pdl_gas <= 0.0 {
+					self.data.pdl_gas = 0.0;
+					self.data.stage = VStage::Maintain;
+				} else if self.data.pdl_gas <= gas_target {
+					self.data.stage = VStage::AccHold;
+				}
 			},
 			VStage::Maintain => {
-
+				if desired_delta > MAINTAIN_BAND {
+					self.data.stage = VStage::AccPush;
+					return self.update_stage(delta_time, target_speed);
+				}
+				if desired_delta < -MAINTAIN_BAND {
+					self.data.stage = VStage::DecPush;
+					return self.update_stage(delta_time, target_speed);
+				}
 			},
 			VStage::DecPush => {
-
+				if desired_delta >= -MAINTAIN_BAND {
+					self.data.stage = match self.data.pdl_gas > 0.0 {
+						true => VStage::AccPull,
+						false => VStage::DecPull
+					};
+					return self.update_stage(delta_time, target_speed);
+				}
+				// RELEASING GAS
+				// The foot only moves over to the break once the gas is up.
+				if self.data.pdl_gas > 0.0 {
+					self.data.pdl_gas = (self.data.pdl_gas - delta_time * GAS_RATE).max(0.0);
+				} else {
+					// PRESSING BREAK
+					let break_target = BREAK_NEUTRAL + (-desired_delta * PEDAL_GAIN).clamp(0.0, 1.0 - BREAK_NEUTRAL);
+					if self.data.pdl_break >= break_target {
+						self.data.stage = VStage::DecHold;
+						return self.update_stage(delta_time, target_speed);
+					}
+					self.data.pdl_break = (self.data.pdl_break + delta_time * BREAK_RATE).min(break_target);
+				}
 			},
 			VStage::DecHold => {
-
+				let break_target = BREAK_NEUTRAL + (-desired_delta * PEDAL_GAIN).clamp(0.0, 1.0 - BREAK_NEUTRAL);
+				if desired_delta >= -MAINTAIN_BAND || self.data.pdl_break > break_target + PEDAL_HOLD {
+					self.data.stage = VStage::DecPull;
+					return self.update_stage(delta_time, target_speed);
+				}
+				if self.data.pdl_break < break_target - PEDAL_HOLD {
+					self.data.stage = VStage::DecPush;
+					return self.update_stage(delta_time, target_speed);
+				}
 			},
 			VStage::DecPull => {
-
+				// RELEASING BREAK
+				// A vehicle that has to stop keeps the break pressed past
+				// neutral, below it the vehicle idles forward.
+				let break_target = match desired_delta < -MAINTAIN_BAND {
+					true => BREAK_NEUTRAL + (-desired_delta * PEDAL_GAIN).clamp(0.0, 1.0 - BREAK_NEUTRAL),
+					false if target_speed <= 0.0 => BREAK_NEUTRAL + PEDAL_HOLD,
+					false => 0.0
+				};
+				self.data.pdl_break = (self.data.pdl_break - delta_time * BREAK_RATE).max(break_target);
+				if self.data.pdl_break <= 0.0 {
+					self.data.pdl_break = 0.0;
+					self.data.stage = VStage::Maintain;
+				} else if self.data.pdl_break <= break_target {
+					self.data.stage = VStage::DecHold;
+				}
 			},
 		}
 		println!("  gas: {}", self.data.pdl_gas);
 		println!("break: {}", self.data.pdl_break);
-		self.data.speed += delta_time * self.data.pdl_gas * self.driver_personality.willing_max_accel;
+		let mut acceleration = self.data.pdl_gas * self.driver_personality.willing_max_accel;
 		
 		let mut decel_pedal = self.data.pdl_break - 0.1;
 		if decel_pedal < 0.0 {
@@ -747,11 +1411,11 @@ impl Vehicle {
 		}
 		let delta_speed = decel_pedal;
 		println!("delta_speed: {}", delta_speed);
-		self.data.speed -= delta_time * delta_speed;//((self.pdl_break - 0.1) * self.driver_personality.willing_max_decel).clamp((self.speed - 20.0).clamp(-20.0, 0.0), 1.0);
-		if self.data.speed <= 0.0 {
-			self.data.speed = 0.0;
+		acceleration -= delta_speed;//((self.pdl_break - 0.1) * self.driver_personality.willing_max_decel).clamp((self.speed - 20.0).clamp(-20.0, 0.0), 1.0);
+		if self.data.speed + delta_time * acceleration <= 0.0 {
 			self.data.stage = VStage::Wait;
 		}
+		acceleration
 	}
 
 	fn calc_signal_target(
@@ -795,13 +1459,39 @@ impl Vehicle {
 
 		let mut min_signal_instruct: InstructSlow = InstructSlow {
 			target_speed: lane_speed,
-			target: VTarget::AvgSpeed
+			target: VTarget::AvgSpeed,
+			stop_distance: None
 		};
 		for signal_instruct in self.signal_instructs.iter() {
 			if signal_instruct.target_speed < min_signal_instruct.target_speed {
 				min_signal_instruct = *signal_instruct;
 			}
 		}
+
+		// WAYPOINT STOP
+
+		let dwell = self.navigation.waypoint().map_or(0.0, |x| x.dwell);
+		if dwell > 0.0 {
+			if let Some(distance) = self.waypoint_distance(allocation) {
+				// Speed from which half the willing deceleration stops the
+				// vehicle at the waypoint.
+				let target_speed = (distance.max(0.0) * self.driver_personality.willing_max_decel).sqrt();
+				if target_speed < min_signal_instruct.target_speed {
+					min_signal_instruct = InstructSlow {
+						target_speed,
+						target: VTarget::DecTStop,
+						stop_distance: None
+					};
+				}
+			}
+		}
+
+		// STOP LINES
+
+		min_signal_instruct.stop_distance = self.signal_instructs.iter().filter_map(
+			|x|
+			x.stop_distance
+		).reduce(f32::min);
 		min_signal_instruct
 	}
 
@@ -834,4 +1524,250 @@ impl VehicleData {
 	pub fn seconds_to_moving(&self, distance: f32, speed: f32) -> f32 {
 		distance / (speed - self.speed)
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use crate::network::{testing, navigation::{Navigation, Waypoint}, following::{FollowingModel, Idm, Gipps}, lane_change::lanes_to_route, restriction::TimeWindow, Network};
+
+	use std::sync::Arc;
+
+	use super::{Vehicle, VehicleBatch, SpawnParams, VehicleKind, Perception, VStage, GAS_RATE, BREAK_NEUTRAL};
+
+	fn numbered(sub: u32) -> Vehicle {
+		let mut vehicle = Vehicle::default();
+		vehicle.data.identity.sub = sub;
+		vehicle
+	}
+
+	#[test]
+	fn batch_slots_follow_removal() {
+		let mut batch = VehicleBatch::new(1);
+		for sub in 1..=4 {
+			batch.push(numbered(sub));
+		}
+		// 4 takes the slot of 2.
+		assert_eq!(batch.remove(2).map(|x| x.data.identity.sub), Some(2));
+		assert!(batch.remove(2).is_none());
+		assert_eq!(batch.len(), 3);
+		for sub in [1, 3, 4] {
+			assert_eq!(batch.vehicle(sub).map(|x| x.data.identity.sub), Some(sub));
+		}
+		batch.vehicle_mut(4).unwrap().data.distance = 7.0;
+		assert_eq!(batch.iter().map(|x| x.data.distance).sum::<f32>(), 7.0);
+	}
+
+	#[test]
+	fn waypoint_at_lane_end_is_not_skipped() {
+		let (network, lanes) = testing::network();
+		let id = Vehicle::with_waypoints(&network, lanes[0], vec![
+			Waypoint { identity: lanes[2], distance: 150.0, dwell: 5.0 },
+			Waypoint { identity: lanes[6], distance: 150.0, dwell: 0.0 }
+		], VehicleKind::Car);
+		testing::with_vehicle(&network, id.sub, |x| {
+			x.data.distance = 149.0;
+			x.data.speed = 400.0;
+		});
+		// Crosses lane 1 and the waypoint on lane 3 in one tick.
+		Network::tick(&network, 0.5);
+		assert_eq!(testing::reached(&network, id.sub), vec![0]);
+		let (lane, distance, dwell) = testing::with_vehicle(&network, id.sub, |x| (x.active_identity.lane, x.data.distance, x.dwell_remaining())).unwrap();
+		assert_eq!(lane, lanes[2].lane);
+		assert!(distance < 150.0);
+		assert!(dwell > 0.0);
+	}
+
+	#[test]
+	fn waypoint_on_lateral_lane() {
+		let (network, lanes) = testing::network();
+		let id = Vehicle::with_waypoints(&network, lanes[0], vec![
+			Waypoint { identity: lanes[0], distance: 5.0, dwell: 0.0 },
+			Waypoint { identity: lanes[1], distance: 140.0, dwell: 1.0 }
+		], VehicleKind::Car);
+		testing::with_vehicle(&network, id.sub, |x| x.following_model = FollowingModel::Idm(Idm::default()));
+		let mut reached: Vec<usize> = Vec::new();
+		for _ in 0..600 {
+			Network::tick(&network, 0.05);
+			reached.extend(testing::reached(&network, id.sub));
+			if reached.len() == 1 {
+				// The second waypoint is a lane change away.
+				let allocation = &network.allocation;
+				let to_route = testing::with_vehicle(&network, id.sub, |x| lanes_to_route(allocation, x, x.active_identity)).unwrap();
+				assert!(to_route.is_some());
+			}
+			if reached.len() == 2 {
+				break;
+			}
+		}
+		assert_eq!(reached, vec![0, 1]);
+		let lane = testing::with_vehicle(&network, id.sub, |x| x.active_identity.lane).unwrap();
+		assert_eq!(lane, lanes[1].lane);
+	}
+
+	#[test]
+	fn lane_plan_is_driven() {
+		let (network, lanes) = testing::network();
+		let id = Vehicle::with_navigation(&network, lanes[0], Navigation {
+			target_identity: lanes[11],
+			lane_routing: true,
+			..Default::default()
+		}, SpawnParams::default());
+		testing::with_vehicle(&network, id.sub, |x| x.following_model = FollowingModel::Idm(Idm::default()));
+		let mut visited: Vec<u32> = Vec::new();
+		for _ in 0..2_000 {
+			Network::tick(&network, 0.05);
+			match testing::with_vehicle(&network, id.sub, |x| x.active_identity.lane) {
+				Some(x) if visited.last() != Some(&x) => visited.push(x),
+				Some(_) => {},
+				None => { break; }
+			}
+		}
+		assert_eq!(visited, vec![1, 2, 4, 9, 12]);
+	}
+
+	#[test]
+	fn closure_ahead_reroutes_mid_lane() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let id = Vehicle::new(&network, lanes[0], lanes[12]);
+		let nav = |network: &Arc<Network>| testing::with_vehicle(network, id.sub, |x| x.navigation.nav.iter().map(
+			|y|
+			y.band
+		).collect::<Vec<u32>>()).unwrap();
+		assert_eq!(nav(&network), vec![2, 3, 5, 6, 9]);
+		for _ in 0..20 {
+			Network::tick(&network, 0.05);
+		}
+		allocation.band(2).write().unwrap().closures.push(TimeWindow::always());
+		Network::tick(&network, 0.05);
+		let lane = testing::with_vehicle(&network, id.sub, |x| x.active_identity.lane).unwrap();
+		assert_eq!(lane, lanes[0].lane);
+		assert_eq!(nav(&network), vec![4, 5, 6, 9]);
+	}
+
+	#[test]
+	fn reaction_time_applies_once() {
+		let (network, lanes) = testing::network();
+		let perceptions = |model: FollowingModel| {
+			let id = Vehicle::new(&network, lanes[0], lanes[6]);
+			testing::with_vehicle(&network, id.sub, |x| {
+				x.following_model = model;
+				x.driver_personality.reaction_time = 1.0;
+			});
+			for _ in 0..10 {
+				Network::tick(&network, 0.05);
+			}
+			testing::with_vehicle(&network, id.sub, |x| x.perceptions.len()).unwrap()
+		};
+		// Gipps reacts late by itself, the driver sees the current state.
+		assert_eq!(perceptions(FollowingModel::Gipps(Gipps::default())), 1);
+		assert!(perceptions(FollowingModel::Idm(Idm::default())) > 1);
+	}
+
+	#[test]
+	fn pedal_lifts_gas_before_breaking() {
+		let mut vehicle = Vehicle::default();
+		vehicle.data.speed = 50.0;
+		vehicle.data.pdl_gas = 0.8;
+		vehicle.data.pdl_break = 0.0;
+		vehicle.data.stage = VStage::AccHold;
+		let mut gas = vehicle.data.pdl_gas;
+		for _ in 0..20 {
+			vehicle.update_stage(0.1, 0.0);
+			assert!(gas - vehicle.data.pdl_gas <= 0.1 * GAS_RATE + 1e-4);
+			if vehicle.data.pdl_gas > 0.0 {
+				assert_eq!(vehicle.data.pdl_break, 0.0);
+			}
+			gas = vehicle.data.pdl_gas;
+		}
+		assert_eq!(vehicle.data.pdl_gas, 0.0);
+		assert!(vehicle.data.pdl_break > BREAK_NEUTRAL);
+	}
+
+	#[test]
+	fn pedal_stands_still_at_target_zero() {
+		let mut vehicle = Vehicle::default();
+		vehicle.data.speed = 20.0;
+		vehicle.data.pdl_break = 0.0;
+		vehicle.data.stage = VStage::Maintain;
+		let mut stopped = false;
+		for _ in 0..2_000 {
+			let acceleration = vehicle.update_stage(0.05, 0.0);
+			vehicle.data.speed = (vehicle.data.speed + 0.05 * acceleration).max(0.0);
+			if stopped {
+				assert_eq!(vehicle.data.speed, 0.0);
+			}
+			stopped |= vehicle.data.speed == 0.0;
+		}
+		assert!(stopped);
+		assert!(vehicle.data.pdl_break >= BREAK_NEUTRAL);
+	}
+
+	#[test]
+	fn waypoint_vehicles_take_their_type() {
+		let (network, lanes) = testing::network();
+		let id = Vehicle::with_waypoints(&network, lanes[0], vec![
+			Waypoint { identity: lanes[6], distance: 150.0, dwell: 0.0 }
+		], VehicleKind::Bus);
+		let (kind, length) = testing::with_vehicle(&network, id.sub, |x| (x.data.vehicle_type, x.data.length)).unwrap();
+		assert_eq!(kind, VehicleKind::Bus);
+		assert_eq!(length, network.allocation.vehicle_types.read().unwrap().get(VehicleKind::Bus).length);
+	}
+
+	#[test]
+	fn spawn_checks_range_and_clearance() {
+		let (network, lanes) = testing::network();
+		let length = network.allocation.lane(lanes[0].lane).read().unwrap().length;
+		let spawn = |distance| Vehicle::spawn(&network, lanes[0], lanes[6], SpawnParams {
+			distance,
+			clearance: 10.0,
+			..Default::default()
+		});
+		assert!(spawn(50.0).is_some());
+		assert!(spawn(55.0).is_none());
+		assert!(spawn(-1.0).is_none());
+		assert!(spawn(length).is_none());
+		assert!(spawn(100.0).is_some());
+		assert!(spawn(length - 1.0).is_some());
+	}
+
+	#[test]
+	fn forward_vehicles_look_past_the_leader() {
+		let (network, lanes) = testing::network();
+		let allocation = &network.allocation;
+		let spawn = |lane, distance| Vehicle::spawn(&network, lane, lanes[6], SpawnParams {
+			distance,
+			..Default::default()
+		}).unwrap();
+		let id = spawn(lanes[0], 10.0);
+		let second = spawn(lanes[2], 50.0);
+		let first = spawn(lanes[0], 100.0);
+		let forward = testing::with_vehicle(&network, id.sub, |x| {
+			x.pull_forward_lanes(allocation);
+			x.pull_forward_vehicles(allocation);
+			x.forward_vehicles.iter().map(
+				|v|
+				v.identity.sub
+			).collect::<Vec<u32>>()
+		}).unwrap();
+		assert_eq!(forward, vec![first.sub, second.sub]);
+	}
+
+	#[test]
+	fn perception_lags_by_the_delay() {
+		let mut vehicle = Vehicle::default();
+		let seen: Vec<f32> = (0..6).map(
+			|x|
+			{
+				let perception = Perception {
+					time: x as f32 * 0.5,
+					leader: None,
+					signal: Default::default()
+				};
+				vehicle.perceive(perception, 1.0).time
+			}
+		).collect();
+		// The oldest one until a full second is buffered.
+		assert_eq!(seen, vec![0.0, 0.0, 0.0, 0.5, 1.0, 1.5]);
+		assert_eq!(vehicle.perceptions.len(), 3);
+	}
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use super::{following::FollowingModel, personality::{PersonalityProfile, Distribution}};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VehicleKind {
+	#[default]
+	Car,
+	Truck,
+	Bus,
+	Motorcycle,
+	// Registered by the user with VehicleTypes::register.
+	Custom(u32),
+}
+
+// Physical dimensions and capabilities shared by all vehicles of a kind.
+// Distances and speeds are in the units of the network.
+#[derive(Debug, Clone)]
+pub struct VehicleType {
+	pub length: f32,
+	pub width: f32,
+	// Kilograms.
+	pub mass: f32,
+	pub max_accel: f32,
+	pub max_decel: f32,
+	// Upper bound of the desired speed, no matter the lane speed.
+	pub max_speed: f32,
+	pub following_model: FollowingModel,
+	// Drivers are drawn from it at spawn.
+	pub personality: PersonalityProfile,
+}
+
+impl Default for VehicleType {
+	fn default() -> Self {
+		Self {
+			length: 10.0,
+			width: 4.0,
+			mass: 1_500.0,
+			max_accel: 20.0,
+			max_decel: 50.0,
+			max_speed: 150.0,
+			following_model: FollowingModel::default(),
+			personality: PersonalityProfile::default()
+		}
+	}
+}
+
+// Registry of the vehicle types by kind. Starts out with the built in
+// kinds, kinds without a type fall back to Car.
+#[derive(Debug)]
+pub struct VehicleTypes {
+	types: HashMap<VehicleKind, VehicleType>,
+}
+
+impl Default for VehicleTypes {
+	fn default() -> Self {
+		let mut types: HashMap<VehicleKind, VehicleType> = HashMap::new();
+		types.insert(VehicleKind::Car, VehicleType::default());
+		types.insert(VehicleKind::Truck, VehicleType {
+			length: 30.0,
+			width: 5.0,
+			mass: 18_000.0,
+			max_accel: 8.0,
+			max_decel: 30.0,
+			max_speed: 80.0,
+			personality: PersonalityProfile {
+				time_headway: Distribution::Normal { mean: 1.8, deviation: 0.3, min: 1.0, max: 3.0 },
+				speed_compliance: Distribution::Normal { mean: 0.95, deviation: 0.05, min: 0.85, max: 1.05 },
+				aggressiveness: Distribution::Uniform { min: 0.0, max: 0.5 },
+				..Default::default()
+			},
+			..Default::default()
+		});
+		types.insert(VehicleKind::Bus, VehicleType {
+			length: 24.0,
+			width: 5.0,
+			mass: 12_000.0,
+			max_accel: 10.0,
+			max_decel: 35.0,
+			max_speed: 90.0,
+			personality: PersonalityProfile {
+				time_headway: Distribution::Normal { mean: 1.6, deviation: 0.3, min: 1.0, max: 3.0 },
+				speed_compliance: Distribution::Normal { mean: 0.95, deviation: 0.05, min: 0.85, max: 1.05 },
+				aggressiveness: Distribution::Uniform { min: 0.0, max: 0.5 },
+				..Default::default()
+			},
+			..Default::default()
+		});
+		types.insert(VehicleKind::Motorcycle, VehicleType {
+			length: 5.0,
+			width: 2.0,
+			mass: 250.0,
+			max_accel: 30.0,
+			max_decel: 50.0,
+			max_speed: 160.0,
+			..Default::default()
+		});
+		Self {
+			types
+		}
+	}
+}
+
+impl VehicleTypes {
+	pub fn register(&mut self, kind: VehicleKind, vehicle_type: VehicleType) {
+		self.types.insert(kind, vehicle_type);
+	}
+
+	pub fn get(&self, kind: VehicleKind) -> VehicleType {
+		match self.types.get(&kind) {
+			Some(x) => x.clone(),
+			None => self.types.get(&VehicleKind::Car).cloned().unwrap_or_default()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{VehicleKind, VehicleType, VehicleTypes};
+
+	#[test]
+	fn unknown_kinds_fall_back_to_car() {
+		let mut types = VehicleTypes::default();
+		let car = types.get(VehicleKind::Car);
+		assert_eq!(types.get(VehicleKind::Custom(3)).length, car.length);
+		types.register(VehicleKind::Custom(3), VehicleType {
+			length: 60.0,
+			..Default::default()
+		});
+		assert_eq!(types.get(VehicleKind::Custom(3)).length, 60.0);
+		assert!(types.get(VehicleKind::Truck).length > car.length);
+	}
+}